@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use udptcp::packet::Packet;
+
+fuzz_target!(|data: &[u8]| {
+    let packet = match Packet::from_bytes(data.into()) {
+        Ok(packet) => packet,
+        Err(_) => return,
+    };
+    // options may lose No-Operations and padding, but the header length
+    // is kept, so a serialized packet parses back into the same one
+    let bytes = packet.clone().into_bytes();
+    assert_eq!(bytes.len(), data.len());
+    let parsed =
+        Packet::from_bytes(bytes).expect("serialized packet does not parse");
+    assert_eq!(parsed, packet);
+});
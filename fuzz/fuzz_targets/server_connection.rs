@@ -0,0 +1,161 @@
+#![no_main]
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use arbitrary::Arbitrary;
+use libfuzzer_sys::fuzz_target;
+use tokio::{runtime, time};
+use udptcp::{
+    packet::{Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq},
+    server::{run_server, ServerConfig},
+    sim::{LinkConfig, Network, SimSocket},
+    socket::{CHUNK_SIZE, MAX_PACKET_SIZE},
+    transport::DatagramTransport,
+};
+
+const PEERS: usize = 3;
+
+#[derive(Arbitrary, Debug)]
+enum Input {
+    Segment {
+        flags: u8,
+        seq: u32,
+        ack: u32,
+        length: u16,
+    },
+    Raw(Vec<u8>),
+}
+
+#[derive(Arbitrary, Debug)]
+struct Step {
+    peer: u8,
+    /// Virtual milliseconds to wait before the datagram is sent
+    delay: u16,
+    input: Input,
+}
+
+impl Input {
+    fn datagram(&self, source: SocketAddr, dest: SocketAddr) -> Vec<u8> {
+        let (flags, seq, ack, length) = match self {
+            Input::Segment {
+                flags,
+                seq,
+                ack,
+                length,
+            } => (*flags, *seq, *ack, *length),
+            Input::Raw(bytes) => return bytes.clone(),
+        };
+        let mut bits = Flags::default();
+        if flags & 1 != 0 {
+            bits = bits.flip_fin();
+        }
+        if flags & 2 != 0 {
+            bits = bits.flip_syn();
+        }
+        if flags & 4 != 0 {
+            bits = bits.flip_rst();
+        }
+        if flags & 16 != 0 {
+            bits = bits.flip_ack();
+        }
+        let length = usize::from(length) % (CHUNK_SIZE + 1);
+        Packet::from(PseudoPacket {
+            source,
+            dest,
+            seq: Seq(seq),
+            extra: PacketExtra {
+                ack: Ack(ack),
+                flags: bits,
+                data: vec![0xa5; length],
+                ..Default::default()
+            },
+        })
+        .into_bytes()
+    }
+
+    fn payload(&self) -> usize {
+        match self {
+            Input::Segment { length, .. } => {
+                usize::from(*length) % (CHUNK_SIZE + 1)
+            }
+            Input::Raw(bytes) => bytes.len(),
+        }
+    }
+}
+
+/// Takes whatever the server sent `peer`. The sink never replies, so no
+/// segment of the server may carry data.
+async fn drain(peer: &SimSocket, server: SocketAddr) {
+    let mut buffer = vec![0; MAX_PACKET_SIZE];
+    while let Ok(received) =
+        time::timeout(Duration::ZERO, peer.recv_from(&mut buffer)).await
+    {
+        let (length, source) = received.unwrap();
+        assert_eq!(source, server, "datagram from a stranger");
+        let packet = Packet::from_bytes(buffer[..length].into())
+            .expect("server sent a malformed segment");
+        assert!(
+            packet.data().is_empty(),
+            "server sent data its sink never replied"
+        );
+    }
+}
+
+async fn run(steps: Vec<Step>) {
+    let network = Network::new(LinkConfig::default(), 0).unwrap();
+    let server = network.bind(SocketAddr::from(([10, 0, 0, 1], 7))).unwrap();
+    let address = server.local_addr().unwrap();
+    let peers: Vec<SimSocket> = (0..PEERS)
+        .map(|_| network.bind(SocketAddr::from(([10, 0, 0, 2], 0))).unwrap())
+        .collect();
+
+    let delivered = Arc::new(Mutex::new(vec![0; PEERS]));
+    let sink = delivered.clone();
+    let ports: Vec<SocketAddr> = peers
+        .iter()
+        .map(|peer| peer.local_addr().unwrap())
+        .collect();
+    let config = ServerConfig {
+        sink: Some(Arc::new(move |peer, data: Vec<u8>| {
+            let index = ports.iter().position(|port| *port == peer).unwrap();
+            sink.lock().unwrap()[index] += data.len();
+        })),
+        ..Default::default()
+    };
+    let task = tokio::spawn(run_server(server, config));
+
+    let mut injected = vec![0; PEERS];
+    for step in steps {
+        time::sleep(Duration::from_millis(step.delay.into())).await;
+        let index = usize::from(step.peer) % PEERS;
+        let peer = &peers[index];
+        let source = peer.local_addr().unwrap();
+        let datagram = step.input.datagram(source, address);
+        peer.send_to(&datagram, address).await.unwrap();
+        injected[index] += step.input.payload();
+        time::sleep(Duration::from_millis(1)).await;
+
+        assert!(!task.is_finished(), "server stopped: {:?}", task.await);
+        for peer in &peers {
+            drain(peer, address).await;
+        }
+        let delivered = delivered.lock().unwrap();
+        for (delivered, injected) in delivered.iter().zip(&injected) {
+            assert!(delivered <= injected, "server made up data");
+        }
+    }
+    task.abort();
+}
+
+fuzz_target!(|steps: Vec<Step>| {
+    runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()
+        .unwrap()
+        .block_on(run(steps));
+});
@@ -0,0 +1,411 @@
+//! Throughput measurement in the style of iperf.
+//!
+//! The client sends generated data over one or more connections, for a
+//! while or up to a number of bytes, and reports at every interval how
+//! much the server acknowledged, how often segments were retransmitted,
+//! how round-trip times were distributed and how large the congestion
+//! window was. The server merely drops the data, see [`Meter`].
+
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt::{self, Display},
+    mem,
+    net::SocketAddr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{
+    client::{connect_udp, run_client, Chunks, ClientConfig},
+    clock,
+    server::Sink,
+    socket::CHUNK_SIZE,
+    stats::{ConnectionStats, Stats},
+};
+
+/// What to measure
+#[derive(Debug, Clone, Copy)]
+pub struct BenchConfig {
+    pub limit: Limit,
+    /// Time between two reports
+    pub interval: Duration,
+    /// Number of connections sending at once
+    pub parallel: usize,
+}
+
+impl Default for BenchConfig {
+    fn default() -> Self {
+        Self {
+            limit: Limit::Time(Duration::from_secs(10)),
+            interval: Duration::from_secs(1),
+            parallel: 1,
+        }
+    }
+}
+
+/// When connections stop sending
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// Once this long has passed since the start
+    Time(Duration),
+    /// Once every connection has sent this many bytes
+    Bytes(u64),
+}
+
+/// How connections did over some time
+#[derive(Debug, Clone, PartialEq)]
+pub struct Report {
+    /// Number of the connection, `None` for all of them together
+    pub connection: Option<usize>,
+    /// Since the start of the measurement
+    pub start: Duration,
+    pub end: Duration,
+    /// Bytes of data acknowledged by the server
+    pub bytes: u64,
+    pub segments: u64,
+    pub retransmissions: u64,
+    /// Round-trip times, `None` if none was sampled
+    pub rtt: Option<RttDistribution>,
+    /// Congestion window at the end, summed over connections
+    pub cwnd: usize,
+}
+
+impl Report {
+    /// Bytes of data acknowledged per second
+    pub fn goodput(&self) -> f64 {
+        let elapsed = (self.end - self.start).as_secs_f64();
+        if elapsed > 0.0 {
+            self.bytes as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    /// Share of the segments sent that were retransmissions
+    pub fn retransmission_rate(&self) -> f64 {
+        if self.segments > 0 {
+            self.retransmissions as f64 / self.segments as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.connection {
+            Some(connection) => write!(f, "[{:>3}]", connection)?,
+            None => write!(f, "[SUM]")?,
+        }
+        write!(
+            f,
+            " {:6.2}-{:<6.2} s  {:>9}  {:>12}  retr {:5.2}%",
+            self.start.as_secs_f64(),
+            self.end.as_secs_f64(),
+            scaled(self.bytes as f64, "B"),
+            scaled(self.goodput() * 8.0, "bit/s"),
+            self.retransmission_rate() * 100.0,
+        )?;
+        if let Some(rtt) = &self.rtt {
+            write!(f, "  rtt {}", rtt)?;
+        }
+        write!(f, "  cwnd {} B", self.cwnd)
+    }
+}
+
+/// `value` with a decimal prefix, e.g. `1.50 MB`
+fn scaled(value: f64, unit: &str) -> String {
+    let prefixes = ["", "k", "M", "G"];
+    let mut value = value;
+    let mut prefix = 0;
+    while value >= 1000.0 && prefix + 1 < prefixes.len() {
+        value /= 1000.0;
+        prefix += 1;
+    }
+    format!("{:.2} {}{}", value, prefixes[prefix], unit)
+}
+
+/// Summary of round-trip time samples
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RttDistribution {
+    pub samples: usize,
+    pub min: Duration,
+    pub median: Duration,
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl RttDistribution {
+    /// `None` without any sample
+    pub fn of(samples: &[Duration]) -> Option<Self> {
+        let mut samples = samples.to_vec();
+        samples.sort_unstable();
+        // nearest rank
+        let percentile = |p: usize| {
+            let rank = (p * samples.len()).div_ceil(100);
+            samples[rank.max(1) - 1]
+        };
+        Some(Self {
+            samples: samples.len(),
+            min: *samples.first()?,
+            median: percentile(50),
+            p90: percentile(90),
+            p99: percentile(99),
+            max: *samples.last()?,
+        })
+    }
+}
+
+impl Display for RttDistribution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+        write!(
+            f,
+            "min/p50/p90/p99/max {:.3}/{:.3}/{:.3}/{:.3}/{:.3} ms",
+            ms(self.min),
+            ms(self.median),
+            ms(self.p90),
+            ms(self.p99),
+            ms(self.max),
+        )
+    }
+}
+
+/// Measures the path to the server at `server` with connections set up by
+/// `config`, handing every interval report to `report` along the way and
+/// returning the reports over the whole measurement, ending with their sum
+/// when there are several connections
+pub async fn run_bench(
+    server: SocketAddr,
+    bench: BenchConfig,
+    config: impl Fn() -> ClientConfig,
+    mut report: impl FnMut(&Report),
+) -> Result<Vec<Report>> {
+    let started = clock::now();
+    let (done, mut finished) = mpsc::unbounded_channel();
+    let mut connections = Vec::new();
+    for number in 1..=bench.parallel {
+        let stats = Stats::default();
+        stats.keep_rtt_samples();
+        let config = ClientConfig {
+            stats: Some(stats.clone()),
+            ..config()
+        };
+        let (socket, peer) = connect_udp(server).await?;
+        let chunks = generate(bench.limit, started);
+        let done = done.clone();
+        tokio::spawn(async move {
+            let result = run_client(socket, peer, chunks, config).await;
+            let _ = done.send((number, result));
+        });
+        connections.push(Connection::new(number, stats));
+    }
+    drop(done);
+
+    let mut reported = Duration::ZERO;
+    let mut running: BTreeSet<usize> = (1..=bench.parallel).collect();
+    let mut failure = None;
+    let mut next = started + bench.interval;
+    while !running.is_empty() {
+        tokio::select! {
+            _ = clock::sleep_until(next) => {
+                let end = clock::now() - started;
+                interval(&mut connections, reported, end, &mut report);
+                reported = end;
+                next += bench.interval;
+            }
+            received = finished.recv() => match received {
+                Some((number, result)) => {
+                    running.remove(&number);
+                    if let Err(err) = result {
+                        warn!(connection = number, %err, "connection failed");
+                        failure.get_or_insert(err);
+                    }
+                }
+                // the tasks left panicked without sending their result
+                None => {
+                    for number in mem::take(&mut running) {
+                        warn!(connection = number, "connection panicked");
+                        failure.get_or_insert_with(|| {
+                            anyhow!("Connection {} panicked", number)
+                        });
+                    }
+                }
+            },
+        }
+    }
+    let end = clock::now() - started;
+    if end > reported {
+        interval(&mut connections, reported, end, &mut report);
+    }
+    if let Some(err) = failure {
+        return Err(err);
+    }
+
+    let mut sum = Tally::default();
+    let mut reports = Vec::new();
+    for connection in &connections {
+        let total = &connection.total;
+        reports.push(total.report(
+            Some(connection.number),
+            Duration::ZERO,
+            end,
+        ));
+        sum.add(total);
+    }
+    if connections.len() > 1 {
+        reports.push(sum.report(None, Duration::ZERO, end));
+    }
+    Ok(reports)
+}
+
+/// Reports on every connection since `start`, along with their sum when
+/// there are several
+fn interval(
+    connections: &mut [Connection],
+    start: Duration,
+    end: Duration,
+    report: &mut impl FnMut(&Report),
+) {
+    let mut sum = Tally::default();
+    for connection in connections.iter_mut() {
+        let tally = connection.interval();
+        report(&tally.report(Some(connection.number), start, end));
+        sum.add(&tally);
+    }
+    if connections.len() > 1 {
+        report(&sum.report(None, start, end));
+    }
+}
+
+struct Connection {
+    number: usize,
+    stats: Stats,
+    /// As of the last report
+    last: ConnectionStats,
+    total: Tally,
+}
+
+impl Connection {
+    fn new(number: usize, stats: Stats) -> Self {
+        Self {
+            number,
+            last: stats.snapshot(),
+            stats,
+            total: Tally::default(),
+        }
+    }
+
+    /// What happened since the last call
+    fn interval(&mut self) -> Tally {
+        let current = self.stats.snapshot();
+        let tally = Tally {
+            bytes: current.bytes_acked - self.last.bytes_acked,
+            segments: current.segments_sent - self.last.segments_sent,
+            retransmissions: current.retransmissions
+                - self.last.retransmissions,
+            rtts: self.stats.take_rtt_samples(),
+            cwnd: current.cwnd,
+        };
+        self.total.add(&tally);
+        self.total.cwnd = current.cwnd;
+        self.last = current;
+        tally
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    bytes: u64,
+    segments: u64,
+    retransmissions: u64,
+    rtts: Vec<Duration>,
+    cwnd: usize,
+}
+
+impl Tally {
+    fn add(&mut self, other: &Tally) {
+        self.bytes += other.bytes;
+        self.segments += other.segments;
+        self.retransmissions += other.retransmissions;
+        self.rtts.extend(&other.rtts);
+        self.cwnd += other.cwnd;
+    }
+
+    fn report(
+        &self,
+        connection: Option<usize>,
+        start: Duration,
+        end: Duration,
+    ) -> Report {
+        Report {
+            connection,
+            start,
+            end,
+            bytes: self.bytes,
+            segments: self.segments,
+            retransmissions: self.retransmissions,
+            rtt: RttDistribution::of(&self.rtts),
+            cwnd: self.cwnd,
+        }
+    }
+}
+
+/// Zeroes up to `limit`, in chunks as large as they get
+fn generate(limit: Limit, started: clock::Instant) -> Chunks {
+    let (tx, rx) = mpsc::channel(1);
+    let (mut remaining, deadline) = match limit {
+        Limit::Time(duration) => (u64::MAX, Some(started + duration)),
+        Limit::Bytes(bytes) => (bytes, None),
+    };
+    tokio::spawn(async move {
+        while remaining > 0 {
+            let length = remaining.min(CHUNK_SIZE as u64) as usize;
+            let timer = clock::sleep_until(deadline.unwrap_or_else(clock::now));
+            tokio::select! {
+                sent = tx.send(Ok(vec![0; length])) => {
+                    if sent.is_err() {
+                        break;
+                    }
+                }
+                _ = timer, if deadline.is_some() => break,
+            }
+            remaining -= length as u64;
+        }
+    });
+    rx
+}
+
+/// Sink dropping the data like [`crate::sink::Discard`], and logging how
+/// much every connection delivered once it is over
+#[derive(Default)]
+pub struct Meter(Mutex<HashMap<SocketAddr, u64>>);
+
+impl Sink for Meter {
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        *self.0.lock().unwrap().entry(peer).or_default() += data.len() as u64;
+    }
+
+    fn close(&self, peer: SocketAddr, clean: bool, stats: &ConnectionStats) {
+        let bytes = self.0.lock().unwrap().remove(&peer).unwrap_or_default();
+        let seconds = stats.duration.as_secs_f64();
+        let goodput = if seconds > 0.0 {
+            bytes as f64 * 8.0 / seconds
+        } else {
+            0.0
+        };
+        info!(
+            %peer,
+            clean,
+            received = %scaled(bytes as f64, "B"),
+            seconds = %format_args!("{:.2}", seconds),
+            goodput = %scaled(goodput, "bit/s"),
+            "connection measured"
+        );
+    }
+}
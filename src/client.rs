@@ -1,83 +1,779 @@
 use std::{
     convert::TryFrom,
+    fmt::{self, Display},
+    future::Future,
     io::{self, Read},
-    net::{SocketAddr, ToSocketAddrs, UdpSocket},
+    net::SocketAddr,
+    path::PathBuf,
+    thread,
+    time::Duration,
 };
 
-use anyhow::Result;
-use itertools::Itertools;
-use rand::Rng;
+use anyhow::{bail, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{ToSocketAddrs, UdpSocket},
+    sync::mpsc::{self, Receiver},
+};
+use tracing::{debug, info, info_span, Instrument, Span};
 
 use crate::{
+    auth::Psk,
+    clock,
+    isn::{Isn, IsnGenerator, Rfc6528},
+    keepalive::KeepAlive,
+    noise::{Cipher, NoiseKeys},
     packet::{Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq},
-    socket::{PacketSocket, CHUNK_SIZE},
+    pcap::Pcap,
+    qlog::{Qlog, Vantage},
+    socket::{PacketSocket, CHUNK_SIZE, RETRANSMISSION_TIMEOUT},
+    stats::Stats,
+    transport::DatagramTransport,
 };
 
-pub fn start_client(address: impl ToSocketAddrs) -> Result<()> {
-    let socket = UdpSocket::bind("0.0.0.0:0")?;
-    socket.connect(address)?;
-    let client = Client::from_udp(socket)?;
-    let seq = rand::thread_rng().gen_range(0..1000);
-    let mut seq = client.start_connection(Seq(seq))?;
-    for chunk in &io::stdin().bytes().chunks(CHUNK_SIZE) {
-        let chunk = chunk.collect::<Result<Vec<_>, _>>()?;
-        seq = client.send_chunk(seq, chunk)?;
+/// Why a segment is deemed lost, as written to the event log
+const RETRANSMISSION_TIMEOUT_EXPIRED: &str = "retransmission_timeout";
+const UNEXPECTED_REPLY: &str = "unexpected_reply";
+
+/// Settings of the client
+#[derive(Default)]
+pub struct ClientConfig {
+    pub psk: Option<Psk>,
+    pub noise: Option<NoiseKeys>,
+    pub keepalive: Option<KeepAlive>,
+    /// Generator of the initial sequence number, RFC 6528 by default
+    pub isn: Option<Isn>,
+    /// Capture of every segment sent and received
+    pub pcap: Option<Pcap>,
+    /// Directory to write an event log of the connection into
+    pub qlog: Option<PathBuf>,
+    /// Handle to follow the statistics of the connection through
+    pub stats: Option<Stats>,
+    /// Where the data the server sends back goes, discarded by default
+    pub output: Option<Output>,
+}
+
+/// Streams standard input to the server at `address` over UDP, writing
+/// what it sends back to standard output unless told otherwise
+pub async fn start_client(
+    address: impl ToSocketAddrs,
+    mut config: ClientConfig,
+) -> Result<()> {
+    config
+        .output
+        .get_or_insert_with(|| Box::new(tokio::io::stdout()));
+    let (socket, peer) = connect_udp(address).await?;
+    run_client(socket, peer, read_chunks(io::stdin()), config).await
+}
+
+/// Binds a UDP socket to talk to the server at `address`, returning it
+/// along with the resolved address of the server
+pub async fn connect_udp(
+    address: impl ToSocketAddrs,
+) -> Result<(UdpSocket, SocketAddr)> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    // connecting makes the local address the one facing the server
+    socket.connect(address).await?;
+    let peer = socket.peer_addr()?;
+    Ok((socket, peer))
+}
+
+/// Data to send, in chunks of at most [`CHUNK_SIZE`] bytes
+pub type Chunks = Receiver<io::Result<Vec<u8>>>;
+
+/// Destination of the data the server sends back, shut down once the
+/// server is done
+pub type Output = Box<dyn AsyncWrite + Unpin + Send + Sync>;
+
+/// Streams `chunks` to the server at `peer` over `transport`
+pub async fn run_client<T: DatagramTransport>(
+    transport: T,
+    peer: SocketAddr,
+    mut chunks: Chunks,
+    config: ClientConfig,
+) -> Result<()> {
+    let isn = config.isn.clone();
+    let mut client = Client::new(transport, peer, config)?;
+    let (source, dest) = (client.header.source, client.header.dest);
+    let seq = match isn {
+        Some(isn) => isn.generate(source, dest),
+        None => Rfc6528::new().generate(source, dest),
+    };
+    let stats = client.stats.clone();
+    stats.start(Some(RETRANSMISSION_TIMEOUT));
+    let span = info_span!("connection", %peer);
+    let result = async {
+        let syn_sent = client.state("SYN-SENT");
+        let mut seq = client.start_connection(seq).instrument(syn_sent).await?;
+        let established = client.state("ESTABLISHED");
+        loop {
+            let chunk = client.next_chunk(&mut chunks, seq);
+            let chunk = match chunk.instrument(established.clone()).await? {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            seq = client
+                .send_chunk(seq, chunk)
+                .instrument(established.clone())
+                .await?;
+        }
+        let fin_wait = client.state("FIN-WAIT");
+        client.end_connection(seq).instrument(fin_wait).await?;
+        client.state("CLOSED");
+        Ok(())
+    }
+    .instrument(span.clone())
+    .await;
+    // the server would otherwise wait for the rest of the connection until
+    // it times out, if ever
+    if result.is_err() && client.handshake.is_some() {
+        let reset = client.header.rst(client.next_seq);
+        if let Err(err) = client.send(reset).await {
+            span.in_scope(|| debug!(%err, "cannot reset the connection"));
+        }
     }
-    client.end_connection(seq)
+    stats.finish();
+    span.in_scope(|| info!(stats = %stats.snapshot(), "connection closed"));
+    result
 }
 
-struct Client {
-    header: Header,
-    socket: Socket,
+/// Reads the input on a separate thread, so that a pending read neither
+/// blocks the connection nor keeps the process from exiting.
+pub fn read_chunks(mut input: impl Read + Send + 'static) -> Chunks {
+    let (tx, rx) = mpsc::channel(1);
+    thread::spawn(move || loop {
+        let mut chunk = vec![0; CHUNK_SIZE];
+        let chunk = match input.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(length) => {
+                chunk.truncate(length);
+                Ok(chunk)
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => Err(err),
+        };
+        let failed = chunk.is_err();
+        if tx.blocking_send(chunk).is_err() || failed {
+            break;
+        }
+    });
+    rx
 }
 
-type Socket = PacketSocket<UdpSocket>;
+/// Reads the input on a task of the runtime
+pub fn read_chunks_async(
+    mut input: impl AsyncRead + Unpin + Send + 'static,
+) -> Chunks {
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let chunk = match input.read(&mut chunk).await {
+                Ok(0) => break,
+                Ok(length) => {
+                    chunk.truncate(length);
+                    Ok(chunk)
+                }
+                Err(err) => Err(err),
+            };
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+    rx
+}
 
-impl Client {
-    fn from_udp(socket: UdpSocket) -> Result<Self> {
+/// How to ping a server
+#[derive(Debug, Clone, Copy)]
+pub struct PingConfig {
+    /// Number of probes, unlimited if `None`
+    pub count: Option<u32>,
+    /// Time between the starts of two probes
+    pub interval: Duration,
+    /// How long a probe may wait for its answer before it counts as lost
+    pub timeout: Duration,
+}
+
+impl Default for PingConfig {
+    fn default() -> Self {
+        Self {
+            count: None,
+            interval: Duration::from_secs(1),
+            timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Outcome of a single probe
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Probe {
+    Answered(Duration),
+    /// Reset by the server, or the port is closed
+    Refused,
+    Lost,
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Probe::Answered(rtt) => {
+                write!(f, "rtt {:.3} ms", rtt.as_secs_f64() * 1000.0)
+            }
+            Probe::Refused => write!(f, "refused"),
+            Probe::Lost => write!(f, "no answer"),
+        }
+    }
+}
+
+/// Outcome of pinging a server
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PingSummary {
+    pub sent: u32,
+    pub refused: u32,
+    /// RTT of every answered probe, in order
+    pub rtts: Vec<Duration>,
+}
+
+impl PingSummary {
+    /// Share of the probes left unanswered
+    pub fn loss(&self) -> f64 {
+        if self.sent == 0 {
+            return 0.0;
+        }
+        1.0 - self.rtts.len() as f64 / self.sent as f64
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.rtts.iter().min().copied()
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        self.rtts.iter().max().copied()
+    }
+
+    pub fn avg(&self) -> Option<Duration> {
+        let count = u32::try_from(self.rtts.len()).ok()?;
+        self.rtts.iter().sum::<Duration>().checked_div(count)
+    }
+
+    /// Population standard deviation
+    pub fn stddev(&self) -> Option<Duration> {
+        let avg = self.avg()?.as_secs_f64();
+        let variance = self
+            .rtts
+            .iter()
+            .map(|rtt| (rtt.as_secs_f64() - avg).powi(2))
+            .sum::<f64>()
+            / self.rtts.len() as f64;
+        Some(Duration::from_secs_f64(variance.sqrt()))
+    }
+}
+
+impl Display for PingSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} probes sent, {} answered, {} refused, {:.1}% loss",
+            self.sent,
+            self.rtts.len(),
+            self.refused,
+            self.loss() * 100.0
+        )?;
+        if let (Some(min), Some(avg), Some(max), Some(stddev)) =
+            (self.min(), self.avg(), self.max(), self.stddev())
+        {
+            let ms = |rtt: Duration| rtt.as_secs_f64() * 1000.0;
+            write!(
+                f,
+                ", rtt min/avg/max/stddev {:.3}/{:.3}/{:.3}/{:.3} ms",
+                ms(min),
+                ms(avg),
+                ms(max),
+                ms(stddev)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures the RTT to the server at `peer` with handshakes that are
+/// aborted as soon as the server answers, handing the outcome of every
+/// probe to `probed`, until `config` says so or `stop` completes
+pub async fn run_ping<T: DatagramTransport>(
+    transport: T,
+    peer: SocketAddr,
+    ping: PingConfig,
+    config: ClientConfig,
+    stop: impl Future<Output = ()>,
+    mut probed: impl FnMut(u32, Probe),
+) -> Result<PingSummary> {
+    let isn = config.isn.clone();
+    let rfc6528 = Rfc6528::new();
+    let mut client = Client::new(transport, peer, config)?;
+    let (source, dest) = (client.header.source, client.header.dest);
+    let mut summary = PingSummary::default();
+    tokio::pin!(stop);
+    while ping.count != Some(summary.sent) {
+        let started = clock::now();
+        let seq = match &isn {
+            Some(isn) => isn.generate(source, dest),
+            None => rfc6528.generate(source, dest),
+        };
+        let probe = client.probe(seq, ping.timeout);
+        let probe = tokio::select! {
+            probe = probe => probe?,
+            _ = &mut stop => break,
+        };
+        summary.sent += 1;
+        match probe {
+            Probe::Answered(rtt) => summary.rtts.push(rtt),
+            Probe::Refused => summary.refused += 1,
+            Probe::Lost => {}
+        }
+        probed(summary.sent, probe);
+        if ping.count == Some(summary.sent) {
+            break;
+        }
+        tokio::select! {
+            _ = clock::sleep_until(started + ping.interval) => {}
+            _ = &mut stop => break,
+        }
+    }
+    Ok(summary)
+}
+
+struct Client<T> {
+    header: Header,
+    socket: PacketSocket<T>,
+    noise: Option<NoiseKeys>,
+    cipher: Option<Cipher>,
+    keepalive: Option<KeepAlive>,
+    /// Next sequence number expected from the server
+    ack: Ack,
+    /// Sequence number following everything sent so far
+    next_seq: Seq,
+    output: Option<Output>,
+    /// Whether the server sent its FIN, which may come before ours
+    finished: bool,
+    /// Final ACK of the handshake, repeated whenever the server retransmits
+    /// its SYN-ACK because the ACK got lost
+    handshake: Option<(Seq, Packet)>,
+    stats: Stats,
+    qlog: Qlog,
+}
+
+impl<T: DatagramTransport> Client<T> {
+    fn new(
+        transport: T,
+        peer: SocketAddr,
+        config: ClientConfig,
+    ) -> Result<Self> {
+        let source = transport.local_addr()?;
+        let qlog = match &config.qlog {
+            Some(directory) => {
+                Qlog::create(directory, Vantage::Client, source, peer)?
+            }
+            None => Qlog::default(),
+        };
         Ok(Self {
-            header: Header::from_udp(&socket)?,
-            socket: PacketSocket::new(socket),
+            header: Header { source, dest: peer },
+            socket: PacketSocket::new(transport, config.psk, config.pcap, None),
+            noise: config.noise,
+            cipher: None,
+            keepalive: config.keepalive,
+            ack: Ack::default(),
+            next_seq: Seq(0),
+            output: config.output,
+            finished: false,
+            handshake: None,
+            stats: config.stats.unwrap_or_default(),
+            qlog,
         })
     }
 
-    fn start_connection(&self, seq: Seq) -> Result<Seq> {
+    async fn start_connection(&mut self, seq: Seq) -> Result<Seq> {
+        let noise = self.noise.take();
+        let mut handshake =
+            noise.as_ref().map(NoiseKeys::initiator).transpose()?;
+        let hello = match &mut handshake {
+            Some(handshake) => handshake.write()?,
+            None => Vec::new(),
+        };
         let new_seq = seq + 1;
-        let ack = loop {
-            self.socket.send(self.header.syn(seq))?;
-            if let Some(packet) = self.socket.recv()? {
-                if let Some(ack) = packet.syn_ack(new_seq) {
-                    break ack;
+        let mut retransmitted = false;
+        let (ack, reply, rtt) = loop {
+            let syn = self.header.syn(seq, &hello);
+            let sent = clock::now();
+            self.send(syn.clone()).await?;
+            let deadline = sent + RETRANSMISSION_TIMEOUT;
+            let trigger = match self.recv(new_seq, Some(deadline)).await? {
+                Some(packet) => {
+                    if packet.rst() {
+                        bail!("Connection refused");
+                    }
+                    let reply = Vec::from(packet.data());
+                    if let Some(ack) = packet.syn_ack(new_seq) {
+                        let rtt = clock::now() - sent;
+                        break (
+                            ack,
+                            reply,
+                            Some(rtt).filter(|_| !retransmitted),
+                        );
+                    }
+                    self.stats.duplicate();
+                    UNEXPECTED_REPLY
                 }
+                None => RETRANSMISSION_TIMEOUT_EXPIRED,
+            };
+            debug!(seq = seq.0, "retransmit SYN");
+            self.qlog.packet_lost(&syn, trigger);
+            self.stats.retransmitted();
+            retransmitted = true;
+        };
+        let finish = match handshake {
+            Some(mut handshake) => {
+                handshake.read(&reply)?;
+                let finish = handshake.write()?;
+                self.cipher = Some(handshake.finish()?);
+                finish
             }
+            None => Vec::new(),
         };
         let new_seq = seq + 1;
-        self.socket.send(self.header.ack(new_seq, ack + 1))?;
+        self.ack = ack + 1;
+        self.next_seq = new_seq;
+        let finish = self.header.ack(new_seq, self.ack, &finish);
+        self.send(finish.clone()).await?;
+        self.handshake = Some((new_seq, finish));
+        if let Some(rtt) = rtt {
+            self.measure(rtt);
+        }
         Ok(new_seq)
     }
 
-    fn send_chunk(&self, seq: Seq, chunk: Vec<u8>) -> Result<Seq> {
+    /// Waits for the next chunk of input, taking whatever the server sends
+    /// meanwhile and probing it while idle
+    async fn next_chunk(
+        &mut self,
+        chunks: &mut Chunks,
+        seq: Seq,
+    ) -> Result<Option<Vec<u8>>> {
+        let idle = |keepalive: KeepAlive| clock::now() + keepalive.idle;
+        let mut deadline = self.keepalive.map(idle);
+        let mut probes = 0;
         loop {
-            self.socket.send(self.header.data(seq, &chunk))?;
-            let expected_ack = seq + u32::try_from(chunk.len())?;
-            if let Some(packet) = self.socket.recv()? {
-                if packet.check_ack(expected_ack) {
-                    break Ok(expected_ack);
+            tokio::select! {
+                chunk = chunks.recv() => break Ok(chunk.transpose()?),
+                received = self.socket.recv_from() => {
+                    let packet = match self.accept(received?, seq).await? {
+                        Some(packet) => packet,
+                        None => continue,
+                    };
+                    // FINs are acknowledged already
+                    if packet.seq() + 1 == self.ack && !packet.fin() {
+                        let ack = self.header.ack(seq, self.ack, &[]);
+                        self.send(ack).await?;
+                    }
+                    deadline = self.keepalive.map(idle);
+                    probes = 0;
+                }
+                _ = clock::sleep_until(deadline.unwrap_or_else(clock::now)),
+                    if deadline.is_some() =>
+                {
+                    let keepalive = self.keepalive.unwrap();
+                    if probes == keepalive.probes {
+                        bail!("Connection timed out");
+                    }
+                    debug!("keep-alive probe");
+                    let probe = self.header.ack(seq - 1, self.ack, &[]);
+                    self.send(probe).await?;
+                    deadline = Some(clock::now() + keepalive.interval);
+                    probes += 1;
                 }
             }
         }
     }
 
-    fn end_connection(&self, seq: Seq) -> Result<()> {
-        let ack = loop {
-            self.socket.send(self.header.fin(seq))?;
-            if let Some(packet) = self.socket.recv()? {
-                if let Some(ack) = packet.fin_ack(seq + 1) {
-                    break ack;
+    async fn send_chunk(&mut self, seq: Seq, chunk: Vec<u8>) -> Result<Seq> {
+        let length = chunk.len();
+        let chunk = match &mut self.cipher {
+            Some(cipher) => cipher.seal(&chunk)?,
+            None => chunk,
+        };
+        let expected_ack = seq + u32::try_from(chunk.len())?;
+        self.next_seq = expected_ack;
+        let mut retransmitted = false;
+        loop {
+            let data = self.header.data(seq, &chunk);
+            let sent = clock::now();
+            self.send(data.clone()).await?;
+            let deadline = sent + RETRANSMISSION_TIMEOUT;
+            let trigger = loop {
+                match self.recv(expected_ack, Some(deadline)).await? {
+                    Some(packet) => {
+                        let carried_data =
+                            !packet.data().is_empty() || packet.fin();
+                        if packet.check_ack(expected_ack) {
+                            if !retransmitted {
+                                self.measure(clock::now() - sent);
+                            }
+                            self.stats.acked(length);
+                            return Ok(expected_ack);
+                        }
+                        // data or FIN of the server, taken already, while
+                        // this segment may still be on its way
+                        if carried_data {
+                            continue;
+                        }
+                        self.stats.duplicate();
+                        break UNEXPECTED_REPLY;
+                    }
+                    None => break RETRANSMISSION_TIMEOUT_EXPIRED,
+                }
+            };
+            debug!(seq = seq.0, length = chunk.len(), "retransmit data");
+            self.qlog.packet_lost(&data, trigger);
+            self.stats.retransmitted();
+            retransmitted = true;
+        }
+    }
+
+    async fn end_connection(&mut self, seq: Seq) -> Result<()> {
+        // an encrypted FIN carries a sealed empty payload, so that a forged
+        // one cannot cut the stream short
+        let tag = match &mut self.cipher {
+            Some(cipher) => cipher.seal(&[])?,
+            None => Vec::new(),
+        };
+        let next_seq = seq + u32::try_from(tag.len())? + 1;
+        self.next_seq = next_seq;
+        let mut retransmitted = false;
+        'fin: loop {
+            let fin = self.header.fin(seq, &tag);
+            let sent = clock::now();
+            self.send(fin.clone()).await?;
+            let deadline = sent + RETRANSMISSION_TIMEOUT;
+            // data of the server may come first and delay the ACK
+            let mut delayed = false;
+            let trigger = loop {
+                match self.recv(next_seq, Some(deadline)).await? {
+                    Some(packet) => {
+                        if packet.clone().check_ack(next_seq) {
+                            if !retransmitted && !delayed {
+                                self.measure(clock::now() - sent);
+                            }
+                            break 'fin;
+                        }
+                        if !packet.data().is_empty() || packet.fin() {
+                            delayed = true;
+                            continue;
+                        }
+                        self.stats.duplicate();
+                        break UNEXPECTED_REPLY;
+                    }
+                    None => break RETRANSMISSION_TIMEOUT_EXPIRED,
+                }
+            };
+            debug!(seq = seq.0, "retransmit FIN");
+            self.qlog.packet_lost(&fin, trigger);
+            self.stats.retransmitted();
+            retransmitted = true;
+        }
+        self.wait_fin(next_seq).await
+    }
+
+    /// Waits for the FIN of the server once ours is acknowledged, taking
+    /// whatever it still sends and probing it while idle
+    async fn wait_fin(&mut self, seq: Seq) -> Result<()> {
+        let idle = |keepalive: KeepAlive| clock::now() + keepalive.idle;
+        let mut deadline = self.keepalive.map(idle);
+        let mut probes = 0;
+        while !self.finished {
+            match self.recv(seq, deadline).await? {
+                Some(_) => {
+                    deadline = self.keepalive.map(idle);
+                    probes = 0;
+                }
+                None => {
+                    let keepalive = self.keepalive.unwrap();
+                    if probes == keepalive.probes {
+                        bail!("Connection timed out");
+                    }
+                    debug!("keep-alive probe");
+                    let probe = self.header.ack(seq - 1, self.ack, &[]);
+                    self.send(probe).await?;
+                    deadline = Some(clock::now() + keepalive.interval);
+                    probes += 1;
                 }
             }
+        }
+        Ok(())
+    }
+
+    /// Sends a SYN and waits up to `timeout` for the SYN-ACK, resetting the
+    /// connection right away
+    async fn probe(&mut self, seq: Seq, timeout: Duration) -> Result<Probe> {
+        let hello = match &self.noise {
+            Some(noise) => noise.initiator()?.write()?,
+            None => Vec::new(),
         };
-        self.socket.send(self.header.ack(seq + 1, ack + 1))
+        let sent = clock::now();
+        self.send(self.header.syn(seq, &hello)).await?;
+        let deadline = sent + timeout;
+        loop {
+            let packet = match self.recv(seq + 1, Some(deadline)).await {
+                Ok(Some(packet)) => packet,
+                Ok(None) => break Ok(Probe::Lost),
+                Err(err) => match err.downcast_ref::<io::Error>() {
+                    // what a connected UDP socket gets of an ICMP error
+                    Some(err)
+                        if err.kind() == io::ErrorKind::ConnectionRefused =>
+                    {
+                        break Ok(Probe::Refused)
+                    }
+                    _ => break Err(err),
+                },
+            };
+            if packet.rst() {
+                break Ok(Probe::Refused);
+            }
+            if packet.flags().is_syn() && packet.flags().is_ack() {
+                // answers to earlier probes are reset as well, so that the
+                // server does not wait for their handshakes to complete
+                let ack = packet.acknowledgment();
+                self.send(self.header.rst(Seq(ack.0))).await?;
+                if packet.syn_ack(seq + 1).is_some() {
+                    break Ok(Probe::Answered(clock::now() - sent));
+                }
+            }
+        }
+    }
+
+    async fn send(&self, packet: Packet) -> Result<()> {
+        self.qlog.packet_sent(&packet);
+        self.stats.sent(&packet);
+        self.socket.send_to(packet, self.header.dest).await
+    }
+
+    /// Span of a phase of the connection, logging the transition into it
+    fn state(&self, name: &'static str) -> Span {
+        let span = info_span!("state", name);
+        span.in_scope(|| info!("state changed"));
+        self.qlog.state_updated(name);
+        self.stats.state(name);
+        span
+    }
+
+    /// Takes an RTT sample of a segment that was never retransmitted
+    fn measure(&mut self, rtt: Duration) {
+        let rtt = self.stats.sample_rtt(rtt);
+        self.qlog.metrics_updated(&rtt);
+    }
+
+    /// Receives a packet from the server, `None` if there is none before
+    /// `deadline`. Data it carries is taken and acknowledged with `seq`.
+    async fn recv(
+        &mut self,
+        seq: Seq,
+        deadline: Option<clock::Instant>,
+    ) -> Result<Option<Packet>> {
+        loop {
+            let received = match deadline {
+                Some(deadline) => {
+                    let left = deadline.saturating_duration_since(clock::now());
+                    match clock::timeout(left, self.socket.recv_from()).await {
+                        Some(received) => received?,
+                        None => return Ok(None),
+                    }
+                }
+                None => self.socket.recv_from().await?,
+            };
+            if let Some(packet) = self.accept(received, seq).await? {
+                return Ok(Some(packet));
+            }
+        }
+    }
+
+    /// Handles a packet received from anyone, `None` if the caller has
+    /// nothing left to do with it
+    async fn accept(
+        &mut self,
+        (packet, address): (Packet, SocketAddr),
+        seq: Seq,
+    ) -> Result<Option<Packet>> {
+        if address != self.header.dest {
+            return Ok(None);
+        }
+        self.qlog.packet_received(&packet);
+        self.stats.received(&packet);
+        if packet.rst() && self.handshake.is_some() {
+            bail!("Connection reset");
+        }
+        let (handshake_seq, finish) = match &self.handshake {
+            Some(handshake) => handshake,
+            None => return Ok(Some(packet)),
+        };
+        if packet.clone().syn_ack(*handshake_seq).is_some() {
+            debug!("retransmit handshake ACK");
+            self.stats.duplicate();
+            self.qlog.packet_lost(finish, UNEXPECTED_REPLY);
+            self.stats.retransmitted();
+            self.send(finish.clone()).await?;
+            return Ok(None);
+        }
+        if packet.fin() {
+            self.take_fin(&packet).await?;
+            self.send(self.header.ack(seq, self.ack, &[])).await?;
+        } else if !packet.data().is_empty() && !self.finished {
+            self.take_data(&packet, seq).await?;
+        }
+        Ok(Some(packet))
+    }
+
+    /// Takes the FIN of the server, shutting the output down, unless it is
+    /// not the next segment expected or its payload fails to open. The FIN
+    /// may come before ours, once the server has nothing more to send.
+    async fn take_fin(&mut self, packet: &Packet) -> Result<()> {
+        if self.finished || packet.seq() != self.ack {
+            return Ok(());
+        }
+        let data = match &mut self.cipher {
+            Some(cipher) => match cipher.open(packet.data()) {
+                Some(data) => data,
+                None => return Ok(()),
+            },
+            None => Vec::from(packet.data()),
+        };
+        if let Some(output) = &mut self.output {
+            output.write_all(&data).await?;
+            output.shutdown().await?;
+        }
+        self.ack = self.ack + u32::try_from(packet.data().len())? + 1;
+        self.finished = true;
+        Ok(())
+    }
+
+    /// Writes the data of the server to the output and acknowledges it,
+    /// only the next segment expected is taken
+    async fn take_data(&mut self, packet: &Packet, seq: Seq) -> Result<()> {
+        if packet.seq() == self.ack {
+            let data = match &mut self.cipher {
+                Some(cipher) => cipher.open(packet.data()),
+                None => Some(Vec::from(packet.data())),
+            };
+            if let Some(data) = data {
+                if let Some(output) = &mut self.output {
+                    output.write_all(&data).await?;
+                }
+                self.ack = self.ack + u32::try_from(packet.data().len())?;
+            }
+        } else {
+            debug!(seq = packet.seq().0, "duplicate data");
+            self.stats.duplicate();
+        }
+        self.send(self.header.ack(seq, self.ack, &[])).await
     }
 }
 
@@ -87,26 +783,20 @@ struct Header {
 }
 
 impl Header {
-    fn from_udp(socket: &UdpSocket) -> Result<Self> {
-        Ok(Self {
-            source: socket.local_addr()?,
-            dest: socket.peer_addr()?,
-        })
-    }
-
-    fn syn(&self, seq: Seq) -> Packet {
+    fn syn(&self, seq: Seq, data: &[u8]) -> Packet {
         Packet::from(PseudoPacket {
             source: self.source,
             dest: self.dest,
             seq,
             extra: PacketExtra {
                 flags: Flags::default().flip_syn(),
+                data: data.into(),
                 ..Default::default()
             },
         })
     }
 
-    fn ack(&self, seq: Seq, ack: Ack) -> Packet {
+    fn ack(&self, seq: Seq, ack: Ack, data: &[u8]) -> Packet {
         Packet::from(PseudoPacket {
             source: self.source,
             dest: self.dest,
@@ -114,18 +804,32 @@ impl Header {
             extra: PacketExtra {
                 ack,
                 flags: Flags::default().flip_ack(),
+                data: data.into(),
                 ..Default::default()
             },
         })
     }
 
-    fn fin(&self, seq: Seq) -> Packet {
+    fn rst(&self, seq: Seq) -> Packet {
+        Packet::from(PseudoPacket {
+            source: self.source,
+            dest: self.dest,
+            seq,
+            extra: PacketExtra {
+                flags: Flags::default().flip_rst(),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn fin(&self, seq: Seq, data: &[u8]) -> Packet {
         Packet::from(PseudoPacket {
             source: self.source,
             dest: self.dest,
             seq,
             extra: PacketExtra {
                 flags: Flags::default().flip_fin(),
+                data: data.into(),
                 ..Default::default()
             },
         })
@@ -0,0 +1,33 @@
+//! Time source of every timer of the protocol.
+//!
+//! The clock follows wall-clock time in a regular runtime. In a runtime
+//! started paused it becomes virtual: it stands still while any task can
+//! make progress and jumps straight to the next timer once all of them
+//! wait, so hours of retransmissions pass in an instant, see
+//! `sim::simulate` with the `sim` feature.
+
+use std::{future::Future, time::Duration};
+
+use tokio::time;
+
+pub use tokio::time::Instant;
+
+pub fn now() -> Instant {
+    Instant::now()
+}
+
+pub async fn sleep(duration: Duration) {
+    time::sleep(duration).await
+}
+
+pub async fn sleep_until(deadline: Instant) {
+    time::sleep_until(deadline).await
+}
+
+/// Runs `future` for at most `duration`, `None` if it did not complete
+pub async fn timeout<F: Future>(
+    duration: Duration,
+    future: F,
+) -> Option<F::Output> {
+    time::timeout(duration, future).await.ok()
+}
@@ -0,0 +1,83 @@
+use std::{
+    hash::Hasher,
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+
+use rand::{thread_rng, Rng};
+use siphasher::sip::SipHasher24;
+
+use crate::{
+    clock::{self, Instant},
+    packet::Seq,
+};
+
+/// Source of initial sequence numbers for new connections.
+///
+/// Implemented for plain closures, so a fixed or scripted sequence can be
+/// injected wherever a generator is expected.
+pub trait IsnGenerator {
+    fn generate(&self, source: SocketAddr, dest: SocketAddr) -> Seq;
+}
+
+impl<F> IsnGenerator for F
+where
+    F: Fn(SocketAddr, SocketAddr) -> Seq,
+{
+    fn generate(&self, source: SocketAddr, dest: SocketAddr) -> Seq {
+        self(source, dest)
+    }
+}
+
+/// Generator shared between connections
+pub type Isn = Arc<dyn IsnGenerator + Send + Sync>;
+
+/// ISN generator from RFC 6528: `ISN = M + F(4-tuple, secret)`, where `M`
+/// is a timer ticking every 4 microseconds and `F` is a keyed hash.
+pub struct Rfc6528 {
+    key: (u64, u64),
+    epoch: Instant,
+}
+
+impl Rfc6528 {
+    pub fn new() -> Self {
+        let mut rng = thread_rng();
+        Self::with_key((rng.gen(), rng.gen()))
+    }
+
+    /// Generator with a fixed secret, for reproducible runs
+    pub fn with_key(key: (u64, u64)) -> Self {
+        Self {
+            key,
+            epoch: clock::now(),
+        }
+    }
+
+    fn timer(&self) -> u32 {
+        (self.epoch.elapsed().as_micros() / 4) as u32
+    }
+
+    fn hash(&self, source: SocketAddr, dest: SocketAddr) -> u32 {
+        let mut hasher = SipHasher24::new_with_keys(self.key.0, self.key.1);
+        for address in &[source, dest] {
+            match address.ip() {
+                IpAddr::V4(ip) => hasher.write(&ip.octets()),
+                IpAddr::V6(ip) => hasher.write(&ip.octets()),
+            }
+            hasher.write_u16(address.port());
+        }
+        hasher.finish() as u32
+    }
+}
+
+impl Default for Rfc6528 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl IsnGenerator for Rfc6528 {
+    fn generate(&self, source: SocketAddr, dest: SocketAddr) -> Seq {
+        Seq(self.timer().wrapping_add(self.hash(source, dest)))
+    }
+}
@@ -0,0 +1,25 @@
+use std::time::Duration;
+
+/// Settings of keep-alive probing, see RFC 1122, section 4.2.3.6.
+///
+/// A probe is a zero-length ACK carrying a sequence number one less than
+/// expected, which the peer has to answer with a regular ACK.
+#[derive(Debug, Clone, Copy)]
+pub struct KeepAlive {
+    /// Time without any traffic before the first probe is sent
+    pub idle: Duration,
+    /// Time between unanswered probes
+    pub interval: Duration,
+    /// Number of unanswered probes after which the connection times out
+    pub probes: u32,
+}
+
+impl KeepAlive {
+    pub fn new(idle: Duration) -> Self {
+        Self {
+            idle,
+            interval: Duration::from_secs(75),
+            probes: 9,
+        }
+    }
+}
@@ -0,0 +1,22 @@
+pub mod auth;
+pub mod bench;
+pub mod client;
+pub mod clock;
+pub mod isn;
+pub mod keepalive;
+pub mod metrics;
+pub mod noise;
+pub mod packet;
+pub mod pcap;
+pub mod qlog;
+pub mod rtt;
+pub mod server;
+#[cfg(feature = "sim")]
+pub mod sim;
+pub mod sink;
+pub mod socket;
+pub mod socks;
+pub mod stats;
+pub mod transfer;
+pub mod transport;
+pub mod tunnel;
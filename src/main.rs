@@ -1,14 +1,56 @@
-mod server;
-mod client;
-mod socket;
-mod packet;
+use std::{
+    io,
+    net::{SocketAddr, ToSocketAddrs},
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
 
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 use clap::clap_app;
+use tokio::{
+    net::{TcpListener, UdpSocket},
+    signal::{
+        self,
+        unix::{signal, SignalKind},
+    },
+};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
 
-use crate::{client::start_client, server::start_server};
+use udptcp::{
+    auth::Psk,
+    bench::{run_bench, BenchConfig, Limit, Meter, Report},
+    client::{
+        connect_udp, read_chunks, run_client, run_ping, start_client,
+        ClientConfig, PingConfig,
+    },
+    keepalive::KeepAlive,
+    metrics::{serve as serve_metrics, Metrics},
+    noise::NoiseKeys,
+    pcap::Pcap,
+    server::{Limits, Listener, ServerConfig, Sink},
+    sink::{Directory, Discard, Echo, Netcat, Stdout},
+    socks::{proxy, Dialer},
+    transfer::{send_file, Uploads},
+    transport::{DatagramTransport, UnixTransport},
+    tunnel::{forward, Backend},
+};
+
+/// How long connections may take to finish once the server is asked to stop
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    // stdout is left to the data the server receives
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env()
+                .unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .with_writer(io::stderr)
+        .init();
 
-fn main() -> Result<()> {
     let matches = clap_app!(tcpudp =>
         (version: "1.0")
         (author: "Pavel Sokolov <sokolov.p64@gmail.com>")
@@ -17,6 +59,90 @@ fn main() -> Result<()> {
         (@arg CLIENT: -c --client "Start as client (default)")
         (@arg HOST: -H --host <HOST> "Hostname")
         (@arg PORT: -p --port <PORT> "Port")
+        (@arg UNIX: --unix +takes_value
+            "Talk over Unix datagram sockets in this directory instead of UDP")
+        (@arg PSK_FILE: --("psk-file") +takes_value
+            "File with a pre-shared key authenticating every packet")
+        (@arg NOISE_KEY: --("noise-key") +takes_value
+            "Encrypt traffic with a Noise static private key from this file, \
+            generated if missing")
+        (@arg NOISE_PEER: --("noise-peer") +takes_value requires[NOISE_KEY]
+            "File with the public key the peer has to present")
+        (@arg PCAP: --pcap +takes_value
+            "Record every segment sent and received into this pcap file")
+        (@arg QLOG: --qlog +takes_value
+            "Write a JSON-lines event log of every connection into this \
+            directory")
+        (@arg KEEPALIVE: --keepalive +takes_value
+            "Probe the peer after this many seconds of idleness")
+        (@arg KEEPALIVE_INTERVAL: --("keepalive-interval") +takes_value
+            requires[KEEPALIVE] "Seconds between unanswered keep-alive probes")
+        (@arg KEEPALIVE_PROBES: --("keepalive-probes") +takes_value
+            requires[KEEPALIVE]
+            "Number of unanswered keep-alive probes before giving up")
+        (@arg BACKLOG: --backlog +takes_value
+            "Maximum number of half-open connections (server only)")
+        (@arg MAX_CONNECTIONS: --("max-connections") +takes_value
+            "Maximum number of concurrent connections (server only)")
+        (@arg METRICS: --metrics +takes_value
+            "Serve Prometheus metrics over HTTP on this address (server only)")
+        (@arg FORWARD: --forward +takes_value conflicts_with[UNIX]
+            "Listen for TCP connections on this address and forward each \
+            over a connection of its own (client only)")
+        (@arg SOCKS: --socks +takes_value conflicts_with[UNIX FORWARD]
+            "Serve SOCKS5 on this address, carrying each request over a \
+            connection of its own (client only)")
+        (@arg BACKEND: --backend +takes_value conflicts_with[SINK OUTPUT_DIR]
+            "Connect every connection to this TCP address, sending back \
+            what it answers (server only)")
+        (@arg DIAL: --dial conflicts_with[BACKEND SINK OUTPUT_DIR]
+            "Connect every connection to the destination a SOCKS5 client \
+            asked for (server only)")
+        (@arg SINK: --sink +takes_value possible_value[stdout discard echo]
+            conflicts_with[OUTPUT_DIR]
+            "Where the data of every connection goes, stdout by default \
+            (server only)")
+        (@arg OUTPUT_DIR: --("output-dir") +takes_value
+            "Write the data of every connection into a file of its own in \
+            this directory (server only)")
+        (@arg RECEIVE_DIR: --("receive-dir") +takes_value
+            conflicts_with[BACKEND DIAL SINK OUTPUT_DIR]
+            "Store the files clients send into this directory (server only)")
+        (@arg NETCAT: --nc
+            conflicts_with[BACKEND DIAL SINK OUTPUT_DIR RECEIVE_DIR]
+            "Talk like nc with a single client, stdin going to it and its \
+            data to stdout, and exit once the connection closes (server \
+            only, the client talks like nc already)")
+        (@arg SHUTDOWN_TIMEOUT: --("shutdown-timeout") +takes_value
+            "Seconds connections may take to finish on SIGINT or SIGTERM \
+            before they are reset (server only)")
+        (@subcommand bench =>
+            (about: "Measure throughput like iperf, the server dropping \
+            what it receives")
+            (@arg TIME: -t --time +takes_value conflicts_with[BYTES]
+                "Seconds to send for, 10 by default (client only)")
+            (@arg BYTES: -n --bytes +takes_value
+                "Bytes to send over every connection (client only)")
+            (@arg INTERVAL: -i --interval +takes_value
+                "Seconds between reports, 1 by default (client only)")
+            (@arg PARALLEL: -P --parallel +takes_value
+                "Number of connections sending at once (client only)")
+        )
+        (@subcommand ping =>
+            (about: "Measure the RTT to the server with handshakes, reset as \
+            soon as it answers")
+            (@arg COUNT: -c --count +takes_value
+                "Number of probes, until interrupted by default")
+            (@arg INTERVAL: -i --interval +takes_value
+                "Seconds between probes, 1 by default")
+            (@arg TIMEOUT: -W --timeout +takes_value
+                "Seconds to wait for an answer, 1 by default")
+        )
+        (@subcommand send =>
+            (about: "Upload a file, which the server checks and stores in \
+            its receive directory")
+            (@arg FILE: +required "File to send")
+        )
     )
     .get_matches();
 
@@ -26,9 +152,246 @@ fn main() -> Result<()> {
         matches.value_of("PORT").unwrap()
     );
 
+    let psk = matches
+        .value_of("PSK_FILE")
+        .map(Psk::from_file)
+        .transpose()?;
+    let noise = matches
+        .value_of("NOISE_KEY")
+        .map(|key| NoiseKeys::load(key, matches.value_of("NOISE_PEER")))
+        .transpose()?;
+    let pcap = matches.value_of("PCAP").map(Pcap::create).transpose()?;
+    let qlog = matches.value_of("QLOG").map(PathBuf::from);
+    let mut keepalive = matches
+        .value_of("KEEPALIVE")
+        .map(|idle| idle.parse().map(Duration::from_secs))
+        .transpose()?
+        .map(KeepAlive::new);
+    if let Some(keepalive) = &mut keepalive {
+        if let Some(interval) = matches.value_of("KEEPALIVE_INTERVAL") {
+            keepalive.interval = Duration::from_secs(interval.parse()?);
+        }
+        if let Some(probes) = matches.value_of("KEEPALIVE_PROBES") {
+            keepalive.probes = probes.parse()?;
+        }
+    }
+
     if matches.is_present("SERVER") {
-        start_server(address)
+        let mut limits = Limits::default();
+        if let Some(backlog) = matches.value_of("BACKLOG") {
+            limits.backlog = backlog.parse()?;
+        }
+        if let Some(max_connections) = matches.value_of("MAX_CONNECTIONS") {
+            limits.max_connections = max_connections.parse()?;
+        }
+        let metrics = match matches.value_of("METRICS") {
+            Some(address) => {
+                let listener = TcpListener::bind(address).await?;
+                let metrics = Arc::new(Metrics::default());
+                tokio::spawn(serve_metrics(listener, metrics.clone()));
+                Some(metrics)
+            }
+            None => None,
+        };
+        let grace = matches
+            .value_of("SHUTDOWN_TIMEOUT")
+            .map(|grace| grace.parse().map(Duration::from_secs))
+            .transpose()?
+            .unwrap_or(SHUTDOWN_TIMEOUT);
+        let netcat = matches
+            .is_present("NETCAT")
+            .then(|| Arc::new(Netcat::default()));
+        if netcat.is_some() {
+            limits.max_connections = 1;
+        }
+        let sink: Arc<dyn Sink> = if let Some(netcat) = &netcat {
+            netcat.clone()
+        } else if matches.subcommand_matches("bench").is_some() {
+            Arc::new(Meter::default())
+        } else if let Some(directory) = matches.value_of("RECEIVE_DIR") {
+            Arc::new(Uploads::create(directory)?)
+        } else if matches.is_present("DIAL") {
+            Arc::new(Dialer::default())
+        } else {
+            match (matches.value_of("BACKEND"), matches.value_of("OUTPUT_DIR"))
+            {
+                (Some(backend), _) => Arc::new(Backend::new(backend)),
+                (None, Some(directory)) => {
+                    Arc::new(Directory::create(directory)?)
+                }
+                (None, None) => match matches.value_of("SINK") {
+                    Some("discard") => Arc::new(Discard),
+                    Some("echo") => Arc::new(Echo::default()),
+                    _ => Arc::new(Stdout),
+                },
+            }
+        };
+        let config = ServerConfig {
+            limits,
+            sink: Some(sink),
+            psk,
+            noise,
+            keepalive,
+            pcap,
+            qlog,
+            metrics,
+            ..Default::default()
+        };
+        match matches.value_of("UNIX") {
+            Some(directory) => {
+                let transport =
+                    UnixTransport::bind(directory, resolve(&address)?)?;
+                serve(transport, config, grace, netcat).await
+            }
+            None => {
+                let socket = UdpSocket::bind(address).await?;
+                serve(socket, config, grace, netcat).await
+            }
+        }
     } else {
-        start_client(address)
+        if matches.is_present("NETCAT") {
+            bail!("--nc is for the server, the client talks like nc already");
+        }
+        let config = move || ClientConfig {
+            psk: psk.clone(),
+            noise: noise.clone(),
+            keepalive,
+            pcap: pcap.clone(),
+            qlog: qlog.clone(),
+            ..Default::default()
+        };
+        if let Some(options) = matches.subcommand_matches("bench") {
+            if matches.is_present("UNIX") {
+                bail!("Benchmarks only run over UDP");
+            }
+            let mut bench = BenchConfig::default();
+            if let Some(time) = options.value_of("TIME") {
+                bench.limit =
+                    Limit::Time(Duration::from_secs_f64(time.parse()?));
+            }
+            if let Some(bytes) = options.value_of("BYTES") {
+                bench.limit = Limit::Bytes(bytes.parse()?);
+            }
+            if let Some(interval) = options.value_of("INTERVAL") {
+                bench.interval = Duration::from_secs_f64(interval.parse()?);
+            }
+            if let Some(parallel) = options.value_of("PARALLEL") {
+                bench.parallel = parallel.parse()?;
+            }
+            let server = resolve(&address)?;
+            let print = |report: &Report| println!("{}", report);
+            let reports = run_bench(server, bench, config, print).await?;
+            println!("{}", "-".repeat(80));
+            reports.iter().for_each(print);
+            return Ok(());
+        }
+        if let Some(options) = matches.subcommand_matches("ping") {
+            let mut ping = PingConfig::default();
+            if let Some(count) = options.value_of("COUNT") {
+                ping.count = Some(count.parse()?);
+            }
+            if let Some(interval) = options.value_of("INTERVAL") {
+                ping.interval = Duration::from_secs_f64(interval.parse()?);
+            }
+            if let Some(timeout) = options.value_of("TIMEOUT") {
+                ping.timeout = Duration::from_secs_f64(timeout.parse()?);
+            }
+            let stop = async {
+                let _ = signal::ctrl_c().await;
+            };
+            let probed =
+                |number, probe| println!("probe {}: {}", number, probe);
+            let summary = match matches.value_of("UNIX") {
+                Some(directory) => {
+                    let peer = resolve(&address)?;
+                    let local = SocketAddr::new(peer.ip(), 0);
+                    let transport = UnixTransport::bind(directory, local)?;
+                    run_ping(transport, peer, ping, config(), stop, probed)
+                        .await?
+                }
+                None => {
+                    let (socket, peer) = connect_udp(&address).await?;
+                    run_ping(socket, peer, ping, config(), stop, probed).await?
+                }
+            };
+            println!("{}", summary);
+            return Ok(());
+        }
+        if let Some(options) = matches.subcommand_matches("send") {
+            let file = options.value_of("FILE").unwrap();
+            return match matches.value_of("UNIX") {
+                Some(directory) => {
+                    let peer = resolve(&address)?;
+                    let local = SocketAddr::new(peer.ip(), 0);
+                    let transport = UnixTransport::bind(directory, local)?;
+                    send_file(transport, peer, file, config()).await
+                }
+                None => {
+                    let (socket, peer) = connect_udp(&address).await?;
+                    send_file(socket, peer, file, config()).await
+                }
+            };
+        }
+        if let Some(local) = matches.value_of("FORWARD") {
+            let listener = TcpListener::bind(local).await?;
+            return forward(listener, resolve(&address)?, config).await;
+        }
+        if let Some(local) = matches.value_of("SOCKS") {
+            let listener = TcpListener::bind(local).await?;
+            return proxy(listener, resolve(&address)?, config).await;
+        }
+        match matches.value_of("UNIX") {
+            Some(directory) => {
+                let peer = resolve(&address)?;
+                let local = SocketAddr::new(peer.ip(), 0);
+                let transport = UnixTransport::bind(directory, local)?;
+                let config = ClientConfig {
+                    output: Some(Box::new(tokio::io::stdout())),
+                    ..config()
+                };
+                run_client(transport, peer, read_chunks(io::stdin()), config)
+                    .await
+            }
+            None => start_client(address, config()).await,
+        }
     }
 }
+
+/// Serves connections until SIGINT or SIGTERM, then gives them `grace` to
+/// finish. A second signal resets them right away.
+async fn serve<T: DatagramTransport>(
+    transport: T,
+    config: ServerConfig,
+    grace: Duration,
+    netcat: Option<Arc<Netcat>>,
+) -> Result<()> {
+    let listener = Listener::new(transport, config);
+    let handle = listener.shutdown_handle();
+    if let Some(netcat) = netcat {
+        let handle = handle.clone();
+        tokio::spawn(async move {
+            netcat.closed().await;
+            handle.shutdown(grace);
+        });
+    }
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::spawn(async move {
+        for grace in [grace, Duration::ZERO] {
+            tokio::select! {
+                _ = signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            handle.shutdown(grace);
+        }
+    });
+    let summary = listener.run().await?;
+    info!(%summary, "server stopped");
+    Ok(())
+}
+
+fn resolve(address: &str) -> Result<SocketAddr> {
+    address
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| anyhow!("Cannot resolve {}", address))
+}
@@ -0,0 +1,269 @@
+//! Totals of the server exported in the Prometheus text format.
+
+use std::{
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+use crate::clock;
+
+/// Upper bounds of the buckets of the handshake latency histogram, in
+/// seconds
+const HANDSHAKE_BUCKETS: [f64; 12] = [
+    0.000_5, 0.001, 0.002_5, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Longest request head the metrics endpoint reads
+const MAX_REQUEST: usize = 8 * 1024;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Metrics of a listener, updated as it goes.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    half_open: AtomicU64,
+    established: AtomicU64,
+    accepted: AtomicU64,
+    refused: AtomicU64,
+    bytes_received: AtomicU64,
+    bytes_sent: AtomicU64,
+    retransmissions: AtomicU64,
+    checksum_failures: AtomicU64,
+    authentication_failures: AtomicU64,
+    malformed_datagrams: AtomicU64,
+    handshake: Histogram,
+}
+
+impl Metrics {
+    pub fn accepted(&self) -> u64 {
+        self.accepted.load(Ordering::Relaxed)
+    }
+
+    pub fn refused(&self) -> u64 {
+        self.refused.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_connections(&self, half_open: usize, established: usize) {
+        self.half_open.store(half_open as u64, Ordering::Relaxed);
+        self.established
+            .store(established as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn accept(&self) {
+        self.accepted.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn refuse(&self) {
+        self.refused.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram of `length` bytes sent
+    pub(crate) fn sent(&self, length: usize) {
+        self.bytes_sent.fetch_add(length as u64, Ordering::Relaxed);
+    }
+
+    /// Counts a datagram of `length` bytes received
+    pub(crate) fn received(&self, length: usize) {
+        self.bytes_received
+            .fetch_add(length as u64, Ordering::Relaxed);
+    }
+
+    pub(crate) fn retransmitted(&self) {
+        self.retransmissions.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn checksum_failed(&self) {
+        self.checksum_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn unauthenticated(&self) {
+        self.authentication_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn malformed(&self) {
+        self.malformed_datagrams.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn handshake_completed(&self, latency: Duration) {
+        self.handshake.observe(latency);
+    }
+
+    /// Renders the metrics in the Prometheus text exposition format
+    pub fn render(&self) -> String {
+        let mut text = String::new();
+        let mut metric =
+            |name: &str, kind: &str, help: &str, value: &AtomicU64| {
+                let value = value.load(Ordering::Relaxed);
+                let _ = writeln!(text, "# HELP udptcp_{} {}", name, help);
+                let _ = writeln!(text, "# TYPE udptcp_{} {}", name, kind);
+                let _ = writeln!(text, "udptcp_{} {}", name, value);
+            };
+        metric(
+            "connections_half_open",
+            "gauge",
+            "Connections in the middle of a handshake.",
+            &self.half_open,
+        );
+        metric(
+            "connections_established",
+            "gauge",
+            "Connections past their handshake.",
+            &self.established,
+        );
+        metric(
+            "connections_accepted_total",
+            "counter",
+            "Connections that completed their handshake.",
+            &self.accepted,
+        );
+        metric(
+            "connections_refused_total",
+            "counter",
+            "Connections refused by admission control.",
+            &self.refused,
+        );
+        metric(
+            "received_bytes_total",
+            "counter",
+            "Bytes of datagrams received.",
+            &self.bytes_received,
+        );
+        metric(
+            "sent_bytes_total",
+            "counter",
+            "Bytes of datagrams sent.",
+            &self.bytes_sent,
+        );
+        metric(
+            "retransmissions_total",
+            "counter",
+            "Segments sent again.",
+            &self.retransmissions,
+        );
+        metric(
+            "checksum_failures_total",
+            "counter",
+            "Segments dropped for a wrong checksum.",
+            &self.checksum_failures,
+        );
+        metric(
+            "authentication_failures_total",
+            "counter",
+            "Segments dropped for a missing or wrong MAC.",
+            &self.authentication_failures,
+        );
+        metric(
+            "malformed_datagrams_total",
+            "counter",
+            "Datagrams dropped for not being a segment.",
+            &self.malformed_datagrams,
+        );
+        self.handshake.render(
+            &mut text,
+            "handshake_duration_seconds",
+            "Time from a SYN to the ACK completing the handshake.",
+        );
+        text
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    /// Observations per bucket, the last one past every bound
+    buckets: [AtomicU64; HANDSHAKE_BUCKETS.len() + 1],
+    sum_nanos: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let seconds = value.as_secs_f64();
+        let bucket = HANDSHAKE_BUCKETS
+            .iter()
+            .position(|bound| seconds <= *bound)
+            .unwrap_or(HANDSHAKE_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_nanos
+            .fetch_add(value.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, text: &mut String, name: &str, help: &str) {
+        let _ = writeln!(text, "# HELP udptcp_{} {}", name, help);
+        let _ = writeln!(text, "# TYPE udptcp_{} histogram", name);
+        let mut count = 0;
+        let bounds = HANDSHAKE_BUCKETS.iter().map(|bound| bound.to_string());
+        for (bucket, bound) in
+            self.buckets.iter().zip(bounds.chain(Some("+Inf".into())))
+        {
+            count += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(
+                text,
+                "udptcp_{}_bucket{{le=\"{}\"}} {}",
+                name, bound, count
+            );
+        }
+        let sum = self.sum_nanos.load(Ordering::Relaxed) as f64 / 1e9;
+        let _ = writeln!(text, "udptcp_{}_sum {}", name, sum);
+        let _ = writeln!(text, "udptcp_{}_count {}", name, count);
+    }
+}
+
+/// Serves `metrics` over HTTP to every client of `listener`, at any path
+pub async fn serve(listener: TcpListener, metrics: Arc<Metrics>) -> Result<()> {
+    info!(address = %listener.local_addr()?, "serving metrics");
+    loop {
+        let (stream, address) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let request =
+                clock::timeout(REQUEST_TIMEOUT, respond(stream, &metrics));
+            match request.await {
+                Some(Ok(())) => {}
+                Some(Err(err)) => {
+                    debug!(%address, %err, "metrics request failed")
+                }
+                None => debug!(%address, "metrics request timed out"),
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &Metrics) -> Result<()> {
+    let mut request = Vec::new();
+    let mut buffer = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        let length = stream.read(&mut buffer).await?;
+        if length == 0 || request.len() + length > MAX_REQUEST {
+            return Ok(());
+        }
+        request.extend_from_slice(&buffer[..length]);
+    }
+    let response = if request.starts_with(b"GET ") {
+        let body = metrics.render();
+        format!(
+            "HTTP/1.1 200 OK\r\n\
+            Content-Type: text/plain; version=0.0.4\r\n\
+            Content-Length: {}\r\n\
+            Connection: close\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        "HTTP/1.1 405 Method Not Allowed\r\n\
+        Allow: GET\r\n\
+        Content-Length: 0\r\n\
+        Connection: close\r\n\r\n"
+            .into()
+    };
+    stream.write_all(response.as_bytes()).await?;
+    Ok(stream.shutdown().await?)
+}
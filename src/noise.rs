@@ -0,0 +1,155 @@
+use std::{
+    fs::{self, OpenOptions},
+    io::Write,
+    os::unix::fs::OpenOptionsExt,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Result};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tracing::info;
+
+use crate::socket::MAX_PACKET_SIZE;
+
+const PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+const KEY_LENGTH: usize = 32;
+/// Length of the AEAD tag every sealed payload grows by
+pub const TAG_LENGTH: usize = 16;
+
+/// Static keys of the encrypted mode.
+///
+/// The XX handshake is carried in the payload of SYN, SYN-ACK and the
+/// final ACK, so it completes together with the regular three-way handshake.
+///
+/// Payloads are sealed, and every FIN carries a sealed empty payload, so the
+/// data delivered can be neither altered nor cut short. Other headers are
+/// not authenticated: forged ACKs or resets can still stall or abort a
+/// connection, which a pre-shared key guards against.
+#[derive(Clone)]
+pub struct NoiseKeys {
+    private: Vec<u8>,
+    peer: Option<Vec<u8>>,
+}
+
+impl NoiseKeys {
+    /// Reads the private key from `key`, generating a key pair into `key`
+    /// and `key.pub` if it does not exist yet. If `peer` is given, the
+    /// remote side has to present the public key stored there.
+    pub fn load(
+        key: impl AsRef<Path>,
+        peer: Option<impl AsRef<Path>>,
+    ) -> Result<Self> {
+        let key = key.as_ref();
+        let private = if key.exists() {
+            fs::read(key)?
+        } else {
+            generate(key)?
+        };
+        if private.len() != KEY_LENGTH {
+            bail!("Noise private key in {:?} is malformed", key);
+        }
+        let peer = peer.map(|peer| fs::read(peer)).transpose()?;
+        if peer.as_ref().is_some_and(|peer| peer.len() != KEY_LENGTH) {
+            bail!("Noise peer public key is malformed");
+        }
+        Ok(Self { private, peer })
+    }
+
+    pub fn initiator(&self) -> Result<Handshake<'_>> {
+        let state = Builder::new(PARAMS.parse()?)
+            .local_private_key(&self.private)
+            .build_initiator()?;
+        Ok(Handshake { state, keys: self })
+    }
+
+    pub fn responder(&self) -> Result<Handshake<'_>> {
+        let state = Builder::new(PARAMS.parse()?)
+            .local_private_key(&self.private)
+            .build_responder()?;
+        Ok(Handshake { state, keys: self })
+    }
+}
+
+fn generate(key: &Path) -> Result<Vec<u8>> {
+    let keypair = Builder::new(PARAMS.parse()?).generate_keypair()?;
+    let mut public = PathBuf::from(key).into_os_string();
+    public.push(".pub");
+    OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(key)?
+        .write_all(&keypair.private)?;
+    fs::write(&public, &keypair.public)?;
+    info!(?key, ?public, "generated Noise key pair");
+    Ok(keypair.private)
+}
+
+pub struct Handshake<'a> {
+    state: HandshakeState,
+    keys: &'a NoiseKeys,
+}
+
+impl Handshake<'_> {
+    pub fn write(&mut self) -> Result<Vec<u8>> {
+        let mut message = vec![0; MAX_PACKET_SIZE];
+        let length = self.state.write_message(&[], &mut message)?;
+        message.truncate(length);
+        Ok(message)
+    }
+
+    pub fn read(&mut self, message: &[u8]) -> Result<()> {
+        let mut payload = vec![0; MAX_PACKET_SIZE];
+        self.state
+            .read_message(message, &mut payload)
+            .map_err(|err| anyhow!("Noise handshake failed: {}", err))?;
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<Cipher> {
+        if let Some(peer) = &self.keys.peer {
+            if self.state.get_remote_static() != Some(&peer[..]) {
+                bail!("Noise peer presented an unexpected static key");
+            }
+        }
+        Ok(Cipher {
+            state: self.state.into_stateless_transport_mode()?,
+            sent: 0,
+            received: 0,
+        })
+    }
+}
+
+/// Transport state of an established encrypted connection.
+///
+/// Segments are delivered in order, so the nonce of a payload is its index
+/// in the stream. A replayed or reordered payload fails to open.
+pub struct Cipher {
+    state: StatelessTransportState,
+    sent: u64,
+    received: u64,
+}
+
+impl Cipher {
+    pub fn seal(&mut self, plaintext: &[u8]) -> Result<Vec<u8>> {
+        let mut message = vec![0; plaintext.len() + TAG_LENGTH];
+        let nonce = self.sent;
+        let length =
+            self.state.write_message(nonce, plaintext, &mut message)?;
+        message.truncate(length);
+        self.sent += 1;
+        Ok(message)
+    }
+
+    /// Returns `None` for payloads that fail authentication
+    pub fn open(&mut self, message: &[u8]) -> Option<Vec<u8>> {
+        let mut plaintext = vec![0; message.len()];
+        let length = self
+            .state
+            .read_message(self.received, message, &mut plaintext)
+            .ok()?;
+        plaintext.truncate(length);
+        self.received += 1;
+        Some(plaintext)
+    }
+}
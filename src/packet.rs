@@ -1,10 +1,14 @@
-use std::{convert::TryInto, net::SocketAddr, ops::Add};
+use std::{
+    convert::TryInto,
+    net::SocketAddr,
+    ops::{Add, Sub},
+};
 
 use anyhow::{anyhow, Result};
 
 use crate::socket::MAX_PACKET_SIZE;
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Packet {
     source: Port,
     dest: Port,
@@ -16,7 +20,7 @@ pub struct Packet {
     window_size: WindowSize,
     checksum: u16,
     urgent: u16,
-    // TODO: support options
+    options: Vec<TcpOption>,
     data: Vec<u8>,
 }
 
@@ -29,43 +33,130 @@ impl Packet {
         let ack = Ack(read_u32(&mut bytes)?);
         let (data_offset, flags) = from_u16(read_u16(&mut bytes)?);
         Ok(Packet {
-            source, dest, seq, ack, data_offset, flags,
+            source,
+            dest,
+            seq,
+            ack,
+            data_offset,
+            flags,
             window_size: WindowSize(read_u16(&mut bytes)?),
             checksum: read_u16(&mut bytes)?,
             urgent: read_u16(&mut bytes)?,
-            // TODO: read options
+            options: read_options(&mut bytes, data_offset)?,
             data: bytes.collect(),
         })
     }
 
     pub fn into_bytes(self) -> Vec<u8> {
-        vec![
+        self.to_bytes()
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [
             Vec::from(self.source.0.to_be_bytes()),
             self.dest.0.to_be_bytes().into(),
             self.seq.0.to_be_bytes().into(),
             self.ack.0.to_be_bytes().into(),
-            be_bytes(self.data_offset, self.flags),
+            be_bytes(self.data_offset, &self.flags),
             self.window_size.0.to_be_bytes().into(),
             self.checksum.to_be_bytes().into(),
             self.urgent.to_be_bytes().into(),
-            // TODO: options' bytes
-            self.data
-        ].concat()
+            options_bytes(&self.options, self.data_offset),
+            self.data.clone(),
+        ]
+        .concat()
+    }
+
+    pub fn options_mut(&mut self) -> &mut [TcpOption] {
+        &mut self.options
     }
 
-    pub fn check_sum(&self) -> bool {
-        // TODO: use checksum
-        true
+    pub fn push_option(&mut self, option: TcpOption) {
+        self.options.push(option);
+        self.data_offset = data_offset(&self.options);
+    }
+
+    pub fn set_checksum(&mut self, checksum: u16) {
+        self.checksum = checksum;
+    }
+
+    /// Recomputes the checksum after the packet was changed
+    pub fn update_checksum(&mut self) {
+        self.checksum = 0;
+        self.checksum = !self.sum();
+    }
+
+    /// Checks the checksum, false if the packet was damaged on its way
+    pub fn verify_checksum(&self) -> bool {
+        self.sum() == 0xffff
+    }
+
+    /// One's complement sum of the segment, as for TCP but without the
+    /// pseudo-header: a side bound to a wildcard address, or behind NAT,
+    /// does not know the addresses its peer sees
+    fn sum(&self) -> u16 {
+        let mut sum: u32 = self
+            .to_bytes()
+            .chunks(2)
+            .map(|word| {
+                let lo = word.get(1).copied().unwrap_or(0);
+                (u32::from(word[0]) << 8) | u32::from(lo)
+            })
+            .sum();
+        while sum > 0xffff {
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    pub fn source_port(&self) -> u16 {
+        self.source.0
+    }
+
+    pub fn dest_port(&self) -> u16 {
+        self.dest.0
     }
 
     pub fn seq(&self) -> Ack {
         Ack(self.seq.0)
     }
 
+    pub fn acknowledgment(&self) -> Ack {
+        self.ack
+    }
+
+    pub fn data_offset(&self) -> u8 {
+        self.data_offset
+    }
+
+    pub fn flags(&self) -> &Flags {
+        &self.flags
+    }
+
+    pub fn window_size(&self) -> u16 {
+        self.window_size.0
+    }
+
+    pub fn checksum(&self) -> u16 {
+        self.checksum
+    }
+
+    pub fn urgent(&self) -> u16 {
+        self.urgent
+    }
+
+    pub fn options(&self) -> &[TcpOption] {
+        &self.options
+    }
+
     pub fn fin(&self) -> bool {
         self.flags.is_fin()
     }
 
+    pub fn rst(&self) -> bool {
+        self.flags.is_rst()
+    }
+
     pub fn data(&self) -> &[u8] {
         &self.data
     }
@@ -82,7 +173,9 @@ impl Packet {
 
     /// returns sequence number of a packet
     pub fn ack(self, expected_ack: Seq) -> Option<Ack> {
-        let Packet { seq, ack, flags, .. } = self;
+        let Packet {
+            seq, ack, flags, ..
+        } = self;
         if flags.is_ack() && ack == expected_ack {
             Some(Ack(seq.0))
         } else {
@@ -92,7 +185,9 @@ impl Packet {
 
     /// returns sequence number of a packet
     pub fn syn_ack(self, expected_ack: Seq) -> Option<Ack> {
-        let Packet { seq, ack, flags, .. } = self;
+        let Packet {
+            seq, ack, flags, ..
+        } = self;
         if flags.is_ack() && flags.is_syn() && ack == expected_ack {
             Some(Ack(seq.0))
         } else {
@@ -102,7 +197,9 @@ impl Packet {
 
     /// returns sequence number of a packet
     pub fn fin_ack(self, expected_ack: Seq) -> Option<Ack> {
-        let Packet { seq, ack, flags, .. } = self;
+        let Packet {
+            seq, ack, flags, ..
+        } = self;
         if flags.is_ack() && flags.is_fin() && ack == expected_ack {
             Some(Ack(seq.0))
         } else {
@@ -117,14 +214,20 @@ impl Packet {
 }
 
 const OFFSET_OFFSET: usize = 12;
-
-fn be_bytes(offset: u8, flags: Flags) -> Vec<u8> {
-    ((u16::from(offset) << OFFSET_OFFSET) | flags.0).to_be_bytes().into()
+/// Header length without options, in 32-bit words
+const MIN_DATA_OFFSET: u8 = 5;
+const MAX_DATA_OFFSET: u8 = 15;
+
+fn be_bytes(offset: u8, flags: &Flags) -> Vec<u8> {
+    ((u16::from(offset) << OFFSET_OFFSET) | flags.0)
+        .to_be_bytes()
+        .into()
 }
 
 fn from_u16(value: u16) -> (u8, Flags) {
     let offset = value >> OFFSET_OFFSET;
-    (offset.try_into().unwrap(), Flags(value & !(offset << OFFSET_OFFSET)))
+    // four bits always fit
+    (offset as u8, Flags(value & !(offset << OFFSET_OFFSET)))
 }
 
 fn read_u16(iter: &mut impl Iterator<Item = u8>) -> Result<u16> {
@@ -139,6 +242,108 @@ fn read_u32(iter: &mut impl Iterator<Item = u8>) -> Result<u32> {
     Ok((u32::from(hi) << 16) | u32::from(lo))
 }
 
+fn read_options(
+    iter: &mut impl Iterator<Item = u8>,
+    data_offset: u8,
+) -> Result<Vec<TcpOption>> {
+    let words = data_offset
+        .checked_sub(MIN_DATA_OFFSET)
+        .ok_or(anyhow!("Data offset is too small"))?;
+    let length = usize::from(words) * 4;
+    let bytes: Vec<u8> = iter.take(length).collect();
+    if bytes.len() != length {
+        return Err(anyhow!("Not enough bytes"));
+    }
+    let mut bytes = bytes.into_iter();
+    let mut options = Vec::new();
+    while let Some(kind) = bytes.next() {
+        match kind {
+            END_OF_OPTIONS => break,
+            NO_OPERATION => continue,
+            _ => {}
+        }
+        let length = bytes.next().ok_or(anyhow!("Not enough bytes"))?;
+        let length = usize::from(length)
+            .checked_sub(2)
+            .ok_or(anyhow!("Option length is too small"))?;
+        let value: Vec<u8> = bytes.by_ref().take(length).collect();
+        if value.len() != length {
+            return Err(anyhow!("Not enough bytes"));
+        }
+        options.push(TcpOption::from_kind(kind, value)?);
+    }
+    Ok(options)
+}
+
+/// Serializes `options`, padding them with End of Option List up to
+/// `data_offset`, which may exceed their length if the packet was parsed
+/// from options with No-Operation or padding in them
+fn options_bytes(options: &[TcpOption], data_offset: u8) -> Vec<u8> {
+    let mut bytes: Vec<u8> =
+        options.iter().flat_map(TcpOption::to_bytes).collect();
+    let header = usize::from(data_offset.saturating_sub(MIN_DATA_OFFSET)) * 4;
+    bytes.resize(header.max(bytes.len().div_ceil(4) * 4), END_OF_OPTIONS);
+    bytes
+}
+
+fn data_offset(options: &[TcpOption]) -> u8 {
+    let words = options_bytes(options, MIN_DATA_OFFSET).len() / 4;
+    let offset = usize::from(MIN_DATA_OFFSET) + words;
+    assert!(offset <= usize::from(MAX_DATA_OFFSET), "Too many options");
+    offset as u8
+}
+
+const END_OF_OPTIONS: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const AUTHENTICATION: u8 = 29;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TcpOption {
+    /// TCP Authentication Option, see RFC 5925
+    Authentication {
+        key_id: u8,
+        next_key_id: u8,
+        mac: Vec<u8>,
+    },
+    Unknown {
+        kind: u8,
+        value: Vec<u8>,
+    },
+}
+
+impl TcpOption {
+    fn from_kind(kind: u8, value: Vec<u8>) -> Result<Self> {
+        Ok(match kind {
+            AUTHENTICATION => {
+                let mut value = value.into_iter();
+                TcpOption::Authentication {
+                    key_id: value.next().ok_or(anyhow!("Not enough bytes"))?,
+                    next_key_id: value
+                        .next()
+                        .ok_or(anyhow!("Not enough bytes"))?,
+                    mac: value.collect(),
+                }
+            }
+            kind => TcpOption::Unknown { kind, value },
+        })
+    }
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let (kind, value) = match self {
+            TcpOption::Authentication {
+                key_id,
+                next_key_id,
+                mac,
+            } => {
+                let value = [&[*key_id, *next_key_id], &mac[..]].concat();
+                (AUTHENTICATION, value)
+            }
+            TcpOption::Unknown { kind, value } => (*kind, value.clone()),
+        };
+        [&[kind, (value.len() + 2) as u8], &value[..]].concat()
+    }
+}
+
 pub struct PseudoPacket {
     pub source: SocketAddr,
     pub dest: SocketAddr,
@@ -149,31 +354,28 @@ pub struct PseudoPacket {
 impl From<PseudoPacket> for Packet {
     fn from(packet: PseudoPacket) -> Self {
         let data_offset = packet.data_offset();
-        let checksum = packet.checksum();
-        Packet {
-            source: packet.source.port().into(),
-            dest: packet.dest.port().into(),
+        let (source, dest) = (packet.source, packet.dest);
+        let mut result = Packet {
+            source: source.port().into(),
+            dest: dest.port().into(),
             seq: packet.seq,
             ack: packet.extra.ack,
             data_offset,
             flags: packet.extra.flags,
             window_size: packet.extra.window_size,
-            checksum,
+            checksum: 0,
             urgent: packet.extra.urgent,
+            options: packet.extra.options,
             data: packet.extra.data,
-        }
+        };
+        result.update_checksum();
+        result
     }
 }
 
 impl PseudoPacket {
     pub fn data_offset(&self) -> u8 {
-        // TODO: use data offset (options support)
-        0
-    }
-
-    pub fn checksum(&self) -> u16 {
-        // TODO: use checksum
-        0
+        data_offset(&self.extra.options)
     }
 }
 
@@ -183,10 +385,11 @@ pub struct PacketExtra {
     pub flags: Flags,
     pub window_size: WindowSize,
     pub urgent: u16,
+    pub options: Vec<TcpOption>,
     pub data: Vec<u8>,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Port(pub u16);
 
 impl From<u16> for Port {
@@ -195,10 +398,19 @@ impl From<u16> for Port {
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone, PartialEq)]
 pub struct Flags(u16);
 
 impl Flags {
+    /// Flags from the low 12 bits of `bits`, the rest is the data offset
+    pub fn from_bits(bits: u16) -> Self {
+        Self(bits & ((1 << OFFSET_OFFSET) - 1))
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
     // pub ecn_nonce: bool,
     // pub cong_win_reduced: bool,
     // pub ecn_echo: bool,
@@ -213,7 +425,14 @@ impl Flags {
     }
 
     // pub push: bool,
-    // pub reset: bool,
+
+    pub fn flip_rst(self) -> Self {
+        Self(self.0 ^ 4)
+    }
+
+    pub fn is_rst(&self) -> bool {
+        self.0 & 4 != 0
+    }
 
     pub fn flip_syn(self) -> Self {
         Self(self.0 ^ 2)
@@ -232,8 +451,8 @@ impl Flags {
     }
 }
 
-#[derive(Debug)]
-pub struct WindowSize(u16);
+#[derive(Debug, Clone, PartialEq)]
+pub struct WindowSize(pub u16);
 
 impl Default for WindowSize {
     fn default() -> Self {
@@ -241,25 +460,33 @@ impl Default for WindowSize {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Seq(pub u32);
 
 impl Add<u32> for Seq {
     type Output = Self;
 
     fn add(self, rhs: u32) -> Self::Output {
-        Self(self.0 + rhs)
+        Self(self.0.wrapping_add(rhs))
+    }
+}
+
+impl Sub<u32> for Seq {
+    type Output = Self;
+
+    fn sub(self, rhs: u32) -> Self::Output {
+        Self(self.0.wrapping_sub(rhs))
     }
 }
 
-#[derive(Debug, Default, Clone, Copy)]
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct Ack(pub u32);
 
 impl Add<u32> for Ack {
     type Output = Self;
 
     fn add(self, rhs: u32) -> Self::Output {
-        Self(self.0 + rhs)
+        Self(self.0.wrapping_add(rhs))
     }
 }
 
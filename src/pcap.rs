@@ -0,0 +1,174 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::{IpAddr, Ipv6Addr, SocketAddr},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tracing::warn;
+
+const MAGIC: u32 = 0xa1b2_c3d4;
+const VERSION: (u16, u16) = (2, 4);
+const SNAPLEN: u32 = 65535;
+/// Link type of packets starting right with an IPv4 or IPv6 header
+const LINKTYPE_RAW: u32 = 101;
+const PROTOCOL_TCP: u8 = 6;
+const TTL: u8 = 64;
+
+/// Capture file in the pcap format, where every segment is wrapped into a
+/// synthesized IP header so that tools decode it as TCP. The checksum of
+/// the segment is rewritten to cover the pseudo-header of that IP header,
+/// as tools expect.
+///
+/// IPv4 addresses are written as IPv4-mapped IPv6 ones if the other side of
+/// a segment is IPv6. Clones record into the same file. A capture that fails
+/// to write is logged and stopped, the connections go on without it.
+#[derive(Clone)]
+pub struct Pcap(Arc<Mutex<Option<Writer>>>);
+
+type Writer = Box<dyn Write + Send>;
+
+impl Pcap {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?))
+    }
+
+    /// Writes the capture into `writer`, starting with the file header
+    pub fn new(writer: impl Write + Send + 'static) -> io::Result<Self> {
+        let mut writer: Writer = Box::new(writer);
+        writer.write_all(&MAGIC.to_le_bytes())?;
+        writer.write_all(&VERSION.0.to_le_bytes())?;
+        writer.write_all(&VERSION.1.to_le_bytes())?;
+        // time zone offset and timestamp accuracy
+        writer.write_all(&[0; 8])?;
+        writer.write_all(&SNAPLEN.to_le_bytes())?;
+        writer.write_all(&LINKTYPE_RAW.to_le_bytes())?;
+        writer.flush()?;
+        Ok(Self(Arc::new(Mutex::new(Some(writer)))))
+    }
+
+    /// Appends a segment, flushing it so the capture can be followed live
+    pub fn record(&self, source: SocketAddr, dest: SocketAddr, segment: &[u8]) {
+        let mut writer = self.0.lock().unwrap();
+        let result = match writer.as_mut() {
+            Some(writer) => write_record(writer, source, dest, segment),
+            None => return,
+        };
+        if let Err(err) = result {
+            warn!(%err, "failed to write the capture, stopped it");
+            *writer = None;
+        }
+    }
+}
+
+fn write_record(
+    writer: &mut Writer,
+    source: SocketAddr,
+    dest: SocketAddr,
+    segment: &[u8],
+) -> io::Result<()> {
+    let segment = tcp_checksummed(source, dest, segment);
+    let packet =
+        [&ip_header(source, dest, segment.len())[..], &segment].concat();
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    let length = packet.len() as u32;
+    writer.write_all(&(time.as_secs() as u32).to_le_bytes())?;
+    writer.write_all(&time.subsec_micros().to_le_bytes())?;
+    writer.write_all(&length.min(SNAPLEN).to_le_bytes())?;
+    writer.write_all(&length.to_le_bytes())?;
+    writer.write_all(&packet[..packet.len().min(SNAPLEN as usize)])?;
+    writer.flush()
+}
+
+fn ip_header(source: SocketAddr, dest: SocketAddr, length: usize) -> Vec<u8> {
+    match (source.ip(), dest.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(dest)) => {
+            let total = (20 + length) as u16;
+            let mut header = [
+                &[0x45, 0][..],
+                &total.to_be_bytes(),
+                // identification, then Don't Fragment
+                &[0, 0, 0x40, 0],
+                &[TTL, PROTOCOL_TCP, 0, 0],
+                &source.octets(),
+                &dest.octets(),
+            ]
+            .concat();
+            let checksum = !ones_complement_sum(&header);
+            header[10..12].copy_from_slice(&checksum.to_be_bytes());
+            header
+        }
+        (source, dest) => [
+            &[0x60, 0, 0, 0][..],
+            &(length as u16).to_be_bytes(),
+            &[PROTOCOL_TCP, TTL],
+            &to_ipv6(source).octets(),
+            &to_ipv6(dest).octets(),
+        ]
+        .concat(),
+    }
+}
+
+/// Offset of the checksum in a TCP header
+const CHECKSUM_OFFSET: usize = 16;
+
+/// `segment` with the checksum of RFC 793, or of RFC 8200 if either of the
+/// addresses is IPv6
+fn tcp_checksummed(
+    source: SocketAddr,
+    dest: SocketAddr,
+    segment: &[u8],
+) -> Vec<u8> {
+    let mut segment = segment.to_vec();
+    if segment.len() < CHECKSUM_OFFSET + 2 {
+        return segment;
+    }
+    segment[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2].fill(0);
+    let length = segment.len() as u32;
+    let pseudo_header = match (source.ip(), dest.ip()) {
+        (IpAddr::V4(source), IpAddr::V4(dest)) => [
+            &source.octets()[..],
+            &dest.octets(),
+            &[0, PROTOCOL_TCP],
+            &(length as u16).to_be_bytes(),
+        ]
+        .concat(),
+        (source, dest) => [
+            &to_ipv6(source).octets()[..],
+            &to_ipv6(dest).octets(),
+            &length.to_be_bytes(),
+            &[0, 0, 0, PROTOCOL_TCP],
+        ]
+        .concat(),
+    };
+    let checksum =
+        !ones_complement_sum(&[&pseudo_header[..], &segment].concat());
+    segment[CHECKSUM_OFFSET..CHECKSUM_OFFSET + 2]
+        .copy_from_slice(&checksum.to_be_bytes());
+    segment
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+fn ones_complement_sum(bytes: &[u8]) -> u16 {
+    let mut sum: u32 = bytes
+        .chunks(2)
+        .map(|word| {
+            let lo = word.get(1).copied().unwrap_or(0);
+            (u32::from(word[0]) << 8) | u32::from(lo)
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
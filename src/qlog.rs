@@ -0,0 +1,187 @@
+//! Per-connection event log in the spirit of qlog, one JSON object per line.
+//!
+//! The first line describes the trace, every following one is an event
+//! with a `time` in milliseconds since the connection started:
+//!
+//! ```text
+//! {"qlog_version":"0.3","qlog_format":"NDJSON","trace":{...}}
+//! {"time":0.0,"name":"connectivity:connection_state_updated","data":{...}}
+//! {"time":0.1,"name":"transport:packet_sent","data":{...}}
+//! ```
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use tracing::warn;
+
+use crate::{clock, packet::Packet, rtt::RttEstimator, socket::CHUNK_SIZE};
+
+/// Side of the connection a log is written from
+#[derive(Debug, Clone, Copy)]
+pub enum Vantage {
+    Client,
+    Server,
+}
+
+impl Vantage {
+    fn name(self) -> &'static str {
+        match self {
+            Vantage::Client => "client",
+            Vantage::Server => "server",
+        }
+    }
+}
+
+/// Event log of a single connection, doing nothing if disabled.
+///
+/// A failure to write is reported once and disables the log rather than
+/// the connection.
+#[derive(Default)]
+pub struct Qlog(Mutex<Option<Writer>>);
+
+struct Writer {
+    file: BufWriter<File>,
+    start: clock::Instant,
+    state: Option<&'static str>,
+}
+
+impl Qlog {
+    /// Starts a log of the connection between `local` and `peer` in a new
+    /// file of `directory`
+    pub fn create(
+        directory: impl AsRef<Path>,
+        vantage: Vantage,
+        local: SocketAddr,
+        peer: SocketAddr,
+    ) -> io::Result<Self> {
+        let reference_time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let name =
+            format!("{}-{}-{}.qlog", reference_time, vantage.name(), peer);
+        let mut file =
+            BufWriter::new(File::create(directory.as_ref().join(name))?);
+        let header = json!({
+            "qlog_version": "0.3",
+            "qlog_format": "NDJSON",
+            "title": "udptcp",
+            "trace": {
+                "vantage_point": { "type": vantage.name() },
+                "common_fields": {
+                    "protocol_type": ["udptcp"],
+                    "reference_time": reference_time as u64,
+                    "time_format": "relative",
+                },
+                "local": local.to_string(),
+                "peer": peer.to_string(),
+            },
+        });
+        writeln!(file, "{}", header)?;
+        file.flush()?;
+        Ok(Self(Mutex::new(Some(Writer {
+            file,
+            start: clock::now(),
+            state: None,
+        }))))
+    }
+
+    pub fn packet_sent(&self, packet: &Packet) {
+        self.event("transport:packet_sent", |_| packet_data(packet));
+    }
+
+    pub fn packet_received(&self, packet: &Packet) {
+        self.event("transport:packet_received", |_| packet_data(packet));
+    }
+
+    /// `packet` is deemed lost and about to be sent again
+    pub fn packet_lost(&self, packet: &Packet, trigger: &str) {
+        self.event("recovery:packet_lost", |_| {
+            let mut data = packet_data(packet);
+            data["trigger"] = trigger.into();
+            data
+        });
+    }
+
+    pub fn metrics_updated(&self, rtt: &RttEstimator) {
+        let millis = |rtt: std::time::Duration| rtt.as_secs_f64() * 1000.0;
+        self.event("recovery:metrics_updated", |_| {
+            json!({
+                "latest_rtt": rtt.latest().map(millis),
+                "min_rtt": rtt.min().map(millis),
+                "smoothed_rtt": rtt.smoothed().map(millis),
+                "rtt_variance": millis(rtt.variance()),
+                // a single segment is ever in flight
+                "congestion_window": CHUNK_SIZE,
+            })
+        });
+    }
+
+    pub fn state_updated(&self, state: &'static str) {
+        self.event("connectivity:connection_state_updated", |writer| {
+            let old = writer.state.replace(state);
+            json!({ "old": old, "new": state })
+        });
+    }
+
+    fn event(&self, name: &str, data: impl FnOnce(&mut Writer) -> Value) {
+        let mut writer = self.0.lock().unwrap();
+        let result = match &mut *writer {
+            Some(writer) => {
+                let time = clock::now() - writer.start;
+                let event = json!({
+                    "time": time.as_secs_f64() * 1000.0,
+                    "name": name,
+                    "data": data(writer),
+                });
+                writeln!(writer.file, "{}", event)
+                    .and_then(|_| writer.file.flush())
+            }
+            None => return,
+        };
+        if let Err(err) = result {
+            warn!(%err, "cannot write qlog, disabling it");
+            *writer = None;
+        }
+    }
+}
+
+fn packet_data(packet: &Packet) -> Value {
+    json!({
+        "header": {
+            "packet_type": packet_type(packet),
+            "seq": packet.seq().0,
+            "ack": packet.acknowledgment().0,
+        },
+        "raw": {
+            "length": usize::from(packet.data_offset()) * 4 + packet.data().len(),
+            "payload_length": packet.data().len(),
+        },
+    })
+}
+
+fn packet_type(packet: &Packet) -> &'static str {
+    let flags = packet.flags();
+    match (
+        flags.is_syn(),
+        flags.is_fin(),
+        flags.is_rst(),
+        flags.is_ack(),
+    ) {
+        (_, _, true, _) => "RST",
+        (true, _, _, true) => "SYN-ACK",
+        (true, _, _, false) => "SYN",
+        (_, true, _, true) => "FIN-ACK",
+        (_, true, _, false) => "FIN",
+        _ if !packet.data().is_empty() => "data",
+        (_, _, _, true) => "ACK",
+        _ => "none",
+    }
+}
@@ -0,0 +1,52 @@
+//! Round-trip time estimation as described in RFC 6298.
+//!
+//! Samples are only taken from segments that were never retransmitted
+//! (Karn's algorithm), since the acknowledgment of a retransmitted segment
+//! cannot be matched with one of its transmissions.
+
+use std::time::Duration;
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RttEstimator {
+    latest: Option<Duration>,
+    min: Option<Duration>,
+    smoothed: Option<Duration>,
+    variance: Duration,
+}
+
+impl RttEstimator {
+    pub fn sample(&mut self, rtt: Duration) {
+        self.latest = Some(rtt);
+        self.min = Some(self.min.map_or(rtt, |min| min.min(rtt)));
+        self.smoothed = Some(match self.smoothed {
+            None => {
+                self.variance = rtt / 2;
+                rtt
+            }
+            Some(smoothed) => {
+                let deviation = smoothed.abs_diff(rtt);
+                self.variance = self.variance * 3 / 4 + deviation / 4;
+                smoothed * 7 / 8 + rtt / 8
+            }
+        });
+    }
+
+    /// Last sample, `None` before the first one
+    pub fn latest(&self) -> Option<Duration> {
+        self.latest
+    }
+
+    pub fn min(&self) -> Option<Duration> {
+        self.min
+    }
+
+    /// SRTT, `None` before the first sample
+    pub fn smoothed(&self) -> Option<Duration> {
+        self.smoothed
+    }
+
+    /// RTTVAR
+    pub fn variance(&self) -> Duration {
+        self.variance
+    }
+}
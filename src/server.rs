@@ -1,36 +1,346 @@
 use std::{
-    borrow::Cow, collections::HashMap, convert::TryFrom, net::SocketAddr,
-    sync::Arc,
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    fmt::{self, Display},
+    future,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
 };
 
-use anyhow::{anyhow, Result};
-use rand::{thread_rng, Rng};
+use anyhow::{anyhow, bail, Result};
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
-    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    sync::mpsc::{
+        self, error::TryRecvError, UnboundedReceiver, UnboundedSender,
+    },
+    sync::Notify,
     task::JoinHandle,
 };
+use tracing::{debug, info, info_span, warn, Instrument, Span};
 
 use crate::{
+    auth::Psk,
+    clock,
+    isn::{Isn, Rfc6528},
+    keepalive::KeepAlive,
+    metrics::Metrics,
+    noise::{Cipher, NoiseKeys},
     packet::{Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq},
-    socket::PacketSocket,
+    pcap::Pcap,
+    qlog::{Qlog, Vantage},
+    sink::Stdout,
+    socket::{
+        PacketSocket, CHUNK_SIZE, MAX_PACKET_SIZE, RETRANSMISSION_TIMEOUT,
+    },
+    stats::{ConnectionStats, Stats},
+    transport::DatagramTransport,
 };
 
-#[tokio::main]
-pub async fn start_server(address: impl ToSocketAddrs) -> Result<()> {
-    let socket = Arc::new(PacketSocket(UdpSocket::bind(address).await?));
-    println!("Started on {:?}", socket.0.local_addr()?);
-    let (tx, rx) = mpsc::unbounded_channel();
-    tokio::spawn(event_listener(tx.clone(), rx, socket.clone()));
-    loop {
-        tx.send(Event::from(socket.recv_from().await?))?;
+/// How long a connection may stay half-open before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Why a segment is deemed lost, as written to the event log: the server
+/// retransmits its data on a timer, and anything else only when the peer
+/// repeats itself instead of moving on.
+const RETRANSMISSION_TIMEOUT_EXPIRED: &str = "retransmission_timeout";
+const UNEXPECTED_REPLY: &str = "unexpected_reply";
+
+/// Bytes queued to be sent back to a peer beyond which [`Reply::ready`]
+/// waits
+const REPLY_BACKLOG: usize = 64 * 1024;
+
+/// How long the server lingers after acknowledging the FIN of a peer it
+/// sent its own FIN to first, re-acknowledging the FIN should it come again
+const TIME_WAIT: Duration = Duration::from_secs(1);
+
+/// Admission limits of the listener.
+///
+/// Half-open connections count towards `max_connections` too, so every
+/// admitted handshake is guaranteed a slot once it completes.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// Maximum number of connections in the middle of a handshake.
+    pub backlog: usize,
+    /// Maximum number of concurrent connections.
+    pub max_connections: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            backlog: 128,
+            max_connections: 1024,
+        }
+    }
+}
+
+/// Destination of the data received by the server.
+///
+/// Implemented for plain closures taking the address of the peer and a
+/// chunk of its data.
+pub trait Sink: Send + Sync {
+    /// Called once the connection with `peer` is established, with a
+    /// handle to send it data back and follow its statistics. Dropping the
+    /// handle right away, as by default, means there is nothing to send.
+    fn open(&self, _peer: SocketAddr, _reply: Reply) {}
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>);
+
+    /// Called once `peer` has sent all of its data. The connection ends
+    /// after every handle to reply to it is dropped, or right away if the
+    /// replies were ended with [`Reply::finish`] already.
+    fn finish(&self, _peer: SocketAddr) {}
+
+    /// Called once the connection with `peer` is over, `clean` if it was
+    /// terminated by an acknowledged FIN
+    fn close(&self, _peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+    }
+}
+
+impl<F> Sink for F
+where
+    F: Fn(SocketAddr, Vec<u8>) + Send + Sync,
+{
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        self(peer, data)
+    }
+}
+
+/// Handle to send data back to a peer, whatever the size of the chunks
+#[derive(Clone)]
+pub struct Reply {
+    sender: UnboundedSender<Queued>,
+    backlog: Arc<Backlog>,
+    stats: Stats,
+}
+
+/// What a sink queues on the replies of a connection
+enum Queued {
+    Data(Vec<u8>),
+    /// Nothing more to send
+    End,
+    Reset,
+}
+
+/// Data queued on the replies of a connection and not sent yet
+#[derive(Default)]
+struct Backlog {
+    bytes: AtomicUsize,
+    drained: Notify,
+}
+
+impl Reply {
+    /// Queues `data` to be sent, `false` if the connection is over
+    pub fn send(&self, data: Vec<u8>) -> bool {
+        let length = data.len();
+        self.backlog.bytes.fetch_add(length, Ordering::Relaxed);
+        if self.sender.send(Queued::Data(data)).is_err() {
+            self.backlog.bytes.fetch_sub(length, Ordering::Relaxed);
+            return false;
+        }
+        true
+    }
+
+    /// Ends the replies: the server sends its FIN once the data queued so
+    /// far is acknowledged, without waiting for the FIN of the peer, and
+    /// drops whatever is sent afterwards
+    pub fn finish(&self) {
+        let _ = self.sender.send(Queued::End);
+    }
+
+    /// Resets the connection, once the segment in flight is acknowledged
+    pub fn reset(&self) {
+        let _ = self.sender.send(Queued::Reset);
+    }
+
+    /// Waits until most of the data queued so far is sent, for producers
+    /// faster than the connection
+    pub async fn ready(&self) {
+        loop {
+            let drained = self.backlog.drained.notified();
+            if self.backlog.bytes.load(Ordering::Relaxed) < REPLY_BACKLOG {
+                return;
+            }
+            tokio::select! {
+                _ = drained => {}
+                _ = self.sender.closed() => return,
+            }
+        }
+    }
+
+    /// Live statistics of the connection, the sink gets their final
+    /// snapshot on close
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+}
+
+/// Settings of the server
+#[derive(Default)]
+pub struct ServerConfig {
+    pub limits: Limits,
+    pub psk: Option<Psk>,
+    pub noise: Option<NoiseKeys>,
+    pub keepalive: Option<KeepAlive>,
+    /// Generator of initial sequence numbers, RFC 6528 by default
+    pub isn: Option<Isn>,
+    /// Where received data goes, written to stdout by default
+    pub sink: Option<Arc<dyn Sink>>,
+    /// Capture of every segment sent and received
+    pub pcap: Option<Pcap>,
+    /// Directory to write an event log of every connection into
+    pub qlog: Option<PathBuf>,
+    /// Metrics to count into, e.g. to serve them, private ones by default
+    pub metrics: Option<Arc<Metrics>>,
+}
+
+/// Serves connections on `address` over UDP
+pub async fn start_server(
+    address: impl ToSocketAddrs,
+    config: ServerConfig,
+) -> Result<()> {
+    run_server(UdpSocket::bind(address).await?, config).await
+}
+
+/// Serves connections arriving through `transport`
+pub async fn run_server<T: DatagramTransport>(
+    transport: T,
+    config: ServerConfig,
+) -> Result<()> {
+    Listener::new(transport, config).run().await?;
+    Ok(())
+}
+
+/// Server of the connections arriving through a transport, until it is
+/// shut down through a [`ShutdownHandle`]
+pub struct Listener<T> {
+    transport: T,
+    config: ServerConfig,
+    tx: UnboundedSender<Event>,
+    rx: UnboundedReceiver<Event>,
+}
+
+/// Handle to shut a [`Listener`] down gracefully
+#[derive(Clone)]
+pub struct ShutdownHandle(UnboundedSender<Event>);
+
+impl ShutdownHandle {
+    /// Makes the listener refuse new connections and wait for the current
+    /// ones to finish. Those still open after `grace` are reset.
+    ///
+    /// Shutting down again can only shorten the deadline.
+    pub fn shutdown(&self, grace: Duration) {
+        let _ = self.0.send(Event::Shutdown(grace));
+    }
+}
+
+/// What a listener went through, as reported once it is shut down
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Summary {
+    pub accepted: u64,
+    pub refused: u64,
+    /// Connections terminated by an acknowledged FIN
+    pub closed: u64,
+    pub failed: u64,
+    /// Connections reset because they outlived the shutdown deadline
+    pub aborted: u64,
+}
+
+impl Summary {
+    /// Counts a connection that is over, logging how it ended
+    fn count(
+        &mut self,
+        address: SocketAddr,
+        result: Result<()>,
+        stats: &ConnectionStats,
+    ) {
+        match result {
+            Ok(()) => {
+                self.closed += 1;
+                info!(peer = %address, %stats, "connection closed")
+            }
+            Err(err) => {
+                self.failed += 1;
+                warn!(peer = %address, %err, %stats, "connection failed")
+            }
+        }
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "accepted {}, refused {}, closed {}, failed {}, aborted {}",
+            self.accepted, self.refused, self.closed, self.failed, self.aborted
+        )
+    }
+}
+
+impl<T: DatagramTransport> Listener<T> {
+    pub fn new(transport: T, config: ServerConfig) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        Self {
+            transport,
+            config,
+            tx,
+            rx,
+        }
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        ShutdownHandle(self.tx.clone())
+    }
+
+    /// Serves connections until shut down and all of them are over
+    pub async fn run(self) -> Result<Summary> {
+        let Self {
+            transport,
+            config,
+            tx,
+            rx,
+        } = self;
+        let metrics = config.metrics.unwrap_or_default();
+        let socket = Arc::new(PacketSocket::new(
+            transport,
+            config.psk,
+            config.pcap,
+            Some(metrics.clone()),
+        ));
+        info!(address = %socket.transport.local_addr()?, "listening");
+        let shared = Shared {
+            socket: socket.clone(),
+            isn: config.isn.unwrap_or_else(|| Arc::new(Rfc6528::new())),
+            noise: config.noise.map(Arc::new),
+            keepalive: config.keepalive,
+            sink: config.sink.unwrap_or_else(|| Arc::new(Stdout)),
+            qlog: config.qlog,
+            metrics,
+        };
+        let mut listener =
+            tokio::spawn(event_listener(tx.clone(), rx, shared, config.limits));
+        loop {
+            tokio::select! {
+                letter = socket.recv_from() => {
+                    tx.send(Event::from(letter?))?;
+                }
+                summary = &mut listener => break summary?,
+            }
+        }
     }
 }
 
 #[derive(Debug)]
 enum Event {
     Receive(SocketAddr, Packet),
+    Established(SocketAddr),
     Close(SocketAddr),
+    Shutdown(Duration),
 }
 
 type Letter = (Packet, SocketAddr);
@@ -41,173 +351,825 @@ impl From<Letter> for Event {
     }
 }
 
-type Connections = HashMap<SocketAddr, ConnectionHandles>;
-type Socket = Arc<PacketSocket<UdpSocket>>;
+type Socket<T> = Arc<PacketSocket<T>>;
+
+/// Resources and settings shared by every connection of the listener
+struct Shared<T> {
+    socket: Socket<T>,
+    isn: Isn,
+    noise: Option<Arc<NoiseKeys>>,
+    keepalive: Option<KeepAlive>,
+    sink: Arc<dyn Sink>,
+    qlog: Option<PathBuf>,
+    metrics: Arc<Metrics>,
+}
+
+impl<T> Clone for Shared<T> {
+    fn clone(&self) -> Self {
+        Self {
+            socket: self.socket.clone(),
+            isn: self.isn.clone(),
+            noise: self.noise.clone(),
+            keepalive: self.keepalive,
+            sink: self.sink.clone(),
+            qlog: self.qlog.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
 
-async fn event_listener(
+async fn event_listener<T: DatagramTransport>(
     tx: UnboundedSender<Event>,
     mut rx: UnboundedReceiver<Event>,
-    socket: Socket,
-) -> Result<()> {
+    shared: Shared<T>,
+    limits: Limits,
+) -> Result<Summary> {
     let mut connections = Connections::default();
+    let mut summary = Summary::default();
+    // set once shutting down
+    let mut deadline: Option<clock::Instant> = None;
+    let socket = shared.socket.clone();
+    let metrics = shared.metrics.clone();
     let on_new_connection = |address| {
         let (ttx, rx) = mpsc::unbounded_channel();
-        Connection::new(tx.clone(), rx, socket.clone(), address)
+        Connection::new(tx.clone(), rx, shared.clone(), address)
             .unwrap()
             .handles(ttx)
     };
-    while let Some(event) = rx.recv().await {
+    loop {
+        let expired = async {
+            match deadline {
+                Some(deadline) => clock::sleep_until(deadline).await,
+                None => future::pending().await,
+            }
+        };
+        let event = tokio::select! {
+            event = rx.recv() => event.unwrap(),
+            _ = expired => break,
+        };
         match event {
-            Event::Receive(address, packet) => connections
-                .entry(address)
-                .or_insert_with_key(|&address| on_new_connection(address))
-                .send(packet)?,
-            Event::Close(address) => {
-                match connections.remove(&address).unwrap().join().await {
-                    Ok(()) => println!("Connection on {:?} closed", address),
-                    Err(err) => {
-                        println!("Connection on {:?} errored: {}", address, err)
+            Event::Receive(address, packet) => {
+                if let Some(handles) = connections.get_mut(&address) {
+                    handles.send(packet);
+                } else if !opens(&packet) {
+                    // a reset is never answered
+                    if !packet.rst() {
+                        debug!(peer = %address, "segment of no connection");
+                        let header = Header::from_socket(&socket, address)?;
+                        socket.send_to(header.reset(&packet)?, address).await?;
                     }
+                } else if deadline.is_none() && connections.admits(&limits) {
+                    let mut handles = on_new_connection(address);
+                    handles.send(packet);
+                    connections.insert(address, handles);
+                } else {
+                    metrics.refuse();
+                    let header = Header::from_socket(&socket, address)?;
+                    let reset = header.rst(packet.seq() + 1);
+                    socket.send_to(reset, address).await?;
+                    warn!(
+                        peer = %address,
+                        refused = metrics.refused(),
+                        shutting_down = deadline.is_some(),
+                        "connection refused"
+                    );
                 }
             }
+            Event::Established(address) => {
+                connections.establish(&address);
+                metrics.accept();
+                info!(
+                    peer = %address,
+                    accepted = metrics.accepted(),
+                    "connection established"
+                );
+            }
+            Event::Close(address) => {
+                let handles = connections.remove(&address).unwrap();
+                let stats = handles.stats.clone();
+                let result = handles.join().await;
+                summary.count(address, result, &stats.snapshot());
+            }
+            Event::Shutdown(grace) => {
+                let at = clock::now() + grace;
+                deadline =
+                    Some(deadline.map_or(at, |deadline| deadline.min(at)));
+                info!(
+                    connections = connections.handles.len(),
+                    ?grace,
+                    "shutting down"
+                );
+            }
+        }
+        connections.report(&metrics);
+        if deadline.is_some() && connections.handles.is_empty() {
+            break;
         }
     }
-    Ok(())
+    // connections that ended before the deadline are not aborted
+    while let Ok(event) = rx.try_recv() {
+        if let Event::Close(address) = event {
+            let handles = connections.remove(&address).unwrap();
+            let stats = handles.stats.clone();
+            let result = handles.join().await;
+            summary.count(address, result, &stats.snapshot());
+        }
+    }
+    for (address, handles) in connections.handles.drain() {
+        handles.task.abort();
+        let stats = handles.stats.clone();
+        // a task only stops at an await, so one that ended meanwhile has
+        // closed its sink already
+        if let Ok(result) = handles.task.await {
+            summary.count(address, result, &stats.snapshot());
+            continue;
+        }
+        stats.finish();
+        let stats = stats.snapshot();
+        shared.sink.close(address, false, &stats);
+        let header = Header::from_socket(&socket, address)?;
+        socket.send_to(header.rst(Ack(0)), address).await?;
+        summary.aborted += 1;
+        warn!(peer = %address, %stats, "connection reset on shutdown");
+    }
+    connections.established = 0;
+    connections.report(&metrics);
+    summary.accepted = metrics.accepted();
+    summary.refused = metrics.refused();
+    Ok(summary)
+}
+
+/// Whether `packet` is a reset within the window of what is expected at
+/// `ack`, see RFC 793, section 3.4. The window spans a single segment.
+fn resets(packet: &Packet, ack: Ack) -> bool {
+    let offset = packet.seq().0.wrapping_sub(ack.0);
+    packet.rst() && offset < MAX_PACKET_SIZE as u32
+}
+
+/// Whether `packet` asks for a new connection
+fn opens(packet: &Packet) -> bool {
+    let flags = packet.flags();
+    flags.is_syn() && !flags.is_ack() && !flags.is_rst()
+}
+
+#[derive(Default)]
+struct Connections {
+    handles: HashMap<SocketAddr, ConnectionHandles>,
+    established: usize,
 }
 
-struct ConnectionHandles(JoinHandle<Result<()>>, UnboundedSender<Packet>);
+impl Connections {
+    fn admits(&self, limits: &Limits) -> bool {
+        let half_open = self.handles.len() - self.established;
+        half_open < limits.backlog
+            && self.handles.len() < limits.max_connections
+    }
+
+    fn report(&self, metrics: &Metrics) {
+        let half_open = self.handles.len() - self.established;
+        metrics.set_connections(half_open, self.established);
+    }
+
+    fn get_mut(
+        &mut self,
+        address: &SocketAddr,
+    ) -> Option<&mut ConnectionHandles> {
+        self.handles.get_mut(address)
+    }
+
+    fn insert(&mut self, address: SocketAddr, handles: ConnectionHandles) {
+        self.handles.insert(address, handles);
+    }
+
+    fn establish(&mut self, address: &SocketAddr) {
+        if let Some(handles) = self.handles.get_mut(address) {
+            if !handles.established {
+                handles.established = true;
+                self.established += 1;
+            }
+        }
+    }
+
+    fn remove(&mut self, address: &SocketAddr) -> Option<ConnectionHandles> {
+        let handles = self.handles.remove(address)?;
+        if handles.established {
+            self.established -= 1;
+        }
+        Some(handles)
+    }
+}
+
+struct ConnectionHandles {
+    task: JoinHandle<Result<()>>,
+    sender: UnboundedSender<Packet>,
+    stats: Stats,
+    established: bool,
+}
 
 impl ConnectionHandles {
-    fn send(&mut self, packet: Packet) -> Result<()> {
-        Ok(self.1.send(packet)?)
+    /// Drops the packet if the connection is already over and about to be
+    /// removed
+    fn send(&mut self, packet: Packet) {
+        let _ = self.sender.send(packet);
     }
 
     async fn join(self) -> Result<()> {
-        Ok(self.0.await??)
+        self.task.await?
     }
 }
 
-struct Connection {
+struct Connection<T> {
     emitter: UnboundedSender<Event>,
     source: Source,
-    socket: ConnSocket,
+    socket: ConnSocket<T>,
     header: Header,
+    isn: Isn,
+    noise: Option<Arc<NoiseKeys>>,
+    cipher: Option<Cipher>,
+    keepalive: Option<KeepAlive>,
+    sink: Arc<dyn Sink>,
+    trace: Trace,
 }
 
-impl Connection {
+impl<T: DatagramTransport> Connection<T> {
     fn new(
         emitter: UnboundedSender<Event>,
         source: UnboundedReceiver<Packet>,
-        socket: Socket,
+        shared: Shared<T>,
         address: SocketAddr,
     ) -> Result<Self> {
+        let header = Header::from_socket(&shared.socket, address)?;
+        let qlog = match &shared.qlog {
+            Some(directory) => {
+                Qlog::create(directory, Vantage::Server, header.source, address)
+                    .unwrap_or_else(|err| {
+                        warn!(peer = %address, %err, "cannot create qlog");
+                        Qlog::default()
+                    })
+            }
+            None => Qlog::default(),
+        };
+        let trace = Trace {
+            qlog: Arc::new(qlog),
+            stats: Stats::default(),
+            metrics: shared.metrics,
+        };
         Ok(Self {
             emitter,
-            source: Source(source),
-            header: Header::from_udp(&socket, address)?,
-            socket: ConnSocket(socket, address),
+            source: Source(source, trace.clone()),
+            header,
+            socket: ConnSocket(shared.socket, address, trace.clone()),
+            isn: shared.isn,
+            noise: shared.noise,
+            cipher: None,
+            keepalive: shared.keepalive,
+            sink: shared.sink,
+            trace,
         })
     }
 
     fn handles(mut self, sender: UnboundedSender<Packet>) -> ConnectionHandles {
-        ConnectionHandles(tokio::spawn(async move {
-            let result = self.task().await;
-            self.close().unwrap();
-            result
-        }), sender)
+        let span = info_span!("connection", peer = %self.header.dest);
+        let stats = self.trace.stats.clone();
+        stats.start(Some(RETRANSMISSION_TIMEOUT));
+        let task = tokio::spawn(
+            async move {
+                let result = self.task().await;
+                if result.is_ok() {
+                    self.state("CLOSED");
+                }
+                self.trace.stats.finish();
+                let stats = self.trace.stats.snapshot();
+                self.sink.close(self.header.dest, result.is_ok(), &stats);
+                self.close().unwrap();
+                result
+            }
+            .instrument(span),
+        );
+        ConnectionHandles {
+            task,
+            sender,
+            stats,
+            established: false,
+        }
     }
 
     async fn task(&mut self) -> Result<()> {
-        let (seq, mut ack) = self.start_connection().await?;
-        while let Some((new_ack, data)) = self.receive_chunk(seq, ack).await? {
+        let syn_received = self.state("SYN-RECEIVED");
+        let started = clock::now();
+        let (seq, mut ack) =
+            clock::timeout(HANDSHAKE_TIMEOUT, self.start_connection())
+                .instrument(syn_received)
+                .await
+                .ok_or(anyhow!("Handshake timed out"))??;
+        let latency = clock::now() - started;
+        self.trace.metrics.handshake_completed(latency);
+        self.emitter.send(Event::Established(self.header.dest))?;
+        let established = self.state("ESTABLISHED");
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let backlog = Arc::new(Backlog::default());
+        let reply = Reply {
+            sender,
+            backlog: backlog.clone(),
+            stats: self.trace.stats.clone(),
+        };
+        self.sink.open(self.header.dest, reply);
+        let mut replies = Replies::new(seq, receiver, backlog);
+        loop {
+            let (new_ack, data) = self
+                .receive_chunk(&mut replies, ack)
+                .instrument(established.clone())
+                .await?;
             ack = new_ack;
-            self.report(format!("{:?}", String::from_utf8(data)));
+            match data {
+                Some(data) => self.sink.receive(self.header.dest, data),
+                None => break,
+            }
         }
-        self.report("received fin");
-        self.terminate_connection(seq, ack).await
+        self.sink.finish(self.header.dest);
+        if !replies.done() {
+            let close_wait = self.state("CLOSE-WAIT");
+            self.send_remaining(&mut replies, ack)
+                .instrument(close_wait)
+                .await?;
+        }
+        if replies.fin_sent {
+            let time_wait = self.state("TIME-WAIT");
+            return self.linger(replies.seq, ack).instrument(time_wait).await;
+        }
+        let last_ack = self.state("LAST-ACK");
+        self.terminate_connection(replies.seq, ack)
+            .instrument(last_ack)
+            .await
     }
 
     async fn start_connection(&mut self) -> Result<(Seq, Ack)> {
         let packet = self.source.receive().await;
-        let packet = packet.ok_or(anyhow!("Broken packet"))?;
+        let hello = Vec::from(packet.data());
         let ack = packet.syn().ok_or(anyhow!("Incorrect packet"))?;
-        let seq = Seq(thread_rng().gen_range(0..1000));
+        let noise = self.noise.clone();
+        let mut handshake =
+            noise.as_deref().map(NoiseKeys::responder).transpose()?;
+        let reply = match &mut handshake {
+            Some(handshake) => {
+                handshake.read(&hello)?;
+                handshake.write()?
+            }
+            None => Vec::new(),
+        };
+        let seq = self.isn.generate(self.header.source, self.header.dest);
         let new_ack = ack + 1;
+        let mut retransmitted = false;
         loop {
-            self.socket.send(self.header.syn_ack(seq, new_ack)).await?;
+            let syn_ack = self.header.syn_ack(seq, new_ack, &reply);
+            let sent = clock::now();
+            self.socket.send(syn_ack.clone()).await?;
             let new_seq = seq + 1;
-            if let Some(packet) = self.source.receive().await {
-                if let Some(new_ack_too) = packet.ack(new_seq) {
-                    if new_ack.0 == new_ack_too.0 {
-                        break Ok((new_seq, new_ack));
+            let packet = self.source.receive().await;
+            if packet.rst() && packet.seq() == new_ack {
+                bail!("Handshake reset");
+            }
+            let finish = Vec::from(packet.data());
+            if let Some(new_ack_too) = packet.ack(new_seq) {
+                if new_ack.0 == new_ack_too.0 {
+                    if let Some(mut handshake) = handshake {
+                        handshake.read(&finish)?;
+                        self.cipher = Some(handshake.finish()?);
                     }
+                    if !retransmitted {
+                        self.measure(clock::now() - sent);
+                    }
+                    break Ok((new_seq, new_ack));
                 }
             }
+            debug!(seq = seq.0, "retransmit SYN-ACK");
+            self.trace.stats.duplicate();
+            self.trace.lost(&syn_ack, UNEXPECTED_REPLY);
+            retransmitted = true;
         }
     }
 
+    /// Receives the next chunk of the client, `None` once it sends its FIN.
+    /// The returned ACK covers the segment.
     async fn receive_chunk(
         &mut self,
-        seq: Seq,
+        replies: &mut Replies,
         ack: Ack,
-    ) -> Result<Option<(Ack, Vec<u8>)>> {
+    ) -> Result<(Ack, Option<Vec<u8>>)> {
         loop {
-            self.socket.send(self.header.ack(seq, ack)).await?;
-            if let Some(packet) = self.source.receive().await {
-                let seq = packet.seq();
-                if seq.0 == ack.0 {
-                    break Ok(if packet.fin() {
-                        None
-                    } else {
-                        let data = packet.data();
-                        let new_ack = ack + u32::try_from(data.len())?;
-                        Some((new_ack, Vec::from(data)))
-                    });
+            self.acknowledge(replies, ack).await?;
+            let packet = loop {
+                if let Some(packet) = self.exchange(replies, ack).await? {
+                    break packet;
+                }
+            };
+            let data = packet.data();
+            if packet.seq() == ack && (packet.fin() || !data.is_empty()) {
+                let new_ack = ack + u32::try_from(data.len())?;
+                // an encrypted FIN carries a sealed empty payload, so that
+                // a forged one cannot cut the stream short
+                let data = match &mut self.cipher {
+                    Some(cipher) => match cipher.open(data) {
+                        Some(data) => data,
+                        None => continue,
+                    },
+                    None => Vec::from(data),
+                };
+                if packet.fin() {
+                    break Ok((new_ack + 1, None));
+                }
+                break Ok((new_ack, Some(data)));
+            }
+            if packet.fin() || !data.is_empty() {
+                self.trace.stats.duplicate();
+            }
+            debug!(ack = ack.0, "duplicate ACK");
+        }
+    }
+
+    /// Sends the replies left once the client has sent its FIN, which `ack`
+    /// acknowledges
+    async fn send_remaining(
+        &mut self,
+        replies: &mut Replies,
+        ack: Ack,
+    ) -> Result<()> {
+        self.acknowledge(replies, ack).await?;
+        while !replies.done() {
+            if let Some(packet) = self.exchange(replies, ack).await? {
+                if packet.fin() || !packet.data().is_empty() {
+                    self.trace.stats.duplicate();
                 }
+                self.acknowledge(replies, ack).await?;
             }
         }
+        Ok(())
+    }
+
+    /// Acknowledges `ack`, along with the next chunk of the replies if
+    /// there is one to send
+    async fn acknowledge(
+        &mut self,
+        replies: &mut Replies,
+        ack: Ack,
+    ) -> Result<()> {
+        if replies.in_flight.is_none() && self.send_reply(replies, ack).await? {
+            return Ok(());
+        }
+        let packet = self.header.ack(replies.next_seq(), ack);
+        self.socket.send(packet).await
+    }
+
+    /// Sends the next chunk of the replies, or the FIN once they are
+    /// ended, `false` if there is nothing to send yet
+    async fn send_reply(
+        &mut self,
+        replies: &mut Replies,
+        ack: Ack,
+    ) -> Result<bool> {
+        let (data, fin) = match replies.next_chunk() {
+            Some(data) => (data, false),
+            None if replies.ended && !replies.fin_sent => (Vec::new(), true),
+            None => return Ok(false),
+        };
+        let length = data.len();
+        // an encrypted FIN carries a sealed empty payload, as in LAST-ACK
+        let data = match &mut self.cipher {
+            Some(cipher) => cipher.seal(&data)?,
+            None => data,
+        };
+        let segment = InFlight {
+            data,
+            length,
+            fin,
+            sent: clock::now(),
+            retransmitted: false,
+        };
+        let packet = self.header.segment(replies.seq, ack, &segment);
+        replies.fin_sent |= fin;
+        replies.in_flight = Some(segment);
+        self.socket.send(packet).await?;
+        Ok(true)
+    }
+
+    /// Waits for the next segment of the client while sending it the
+    /// replies, `None` if the replies moved on first
+    async fn exchange(
+        &mut self,
+        replies: &mut Replies,
+        ack: Ack,
+    ) -> Result<Option<Packet>> {
+        loop {
+            if replies.reset {
+                self.socket.send(self.header.rst(ack)).await?;
+                bail!("Connection reset by the sink");
+            }
+            if replies.in_flight.is_none() {
+                self.send_reply(replies, ack).await?;
+            }
+            let deadline = replies
+                .in_flight
+                .as_ref()
+                .map(|segment| segment.sent + RETRANSMISSION_TIMEOUT);
+            let next_seq = replies.next_seq();
+            tokio::select! {
+                packet = self.receive_alive(replies.seq, ack) => {
+                    let packet = match packet? {
+                        Some(packet) => packet,
+                        None => continue,
+                    };
+                    if resets(&packet, ack) {
+                        bail!("Connection reset");
+                    }
+                    if let Some(segment) = replies.in_flight.take() {
+                        if packet.clone().check_ack(next_seq) {
+                            if !segment.retransmitted {
+                                self.measure(clock::now() - segment.sent);
+                            }
+                            self.trace.stats.acked(segment.length);
+                            replies.seq = next_seq;
+                            if !packet.fin() && packet.data().is_empty() {
+                                return Ok(None);
+                            }
+                        } else {
+                            replies.in_flight = Some(segment);
+                        }
+                    }
+                    return Ok(Some(packet));
+                }
+                queued = Replies::recv(&mut replies.receiver),
+                    if replies.in_flight.is_none() =>
+                {
+                    if replies.take(queued) {
+                        return Ok(None);
+                    }
+                }
+                _ = clock::sleep_until(deadline.unwrap_or_else(clock::now)),
+                    if deadline.is_some() =>
+                {
+                    if let Some(segment) = &mut replies.in_flight {
+                        let packet =
+                            self.header.segment(replies.seq, ack, segment);
+                        debug!(
+                            seq = replies.seq.0,
+                            length = segment.data.len(),
+                            fin = segment.fin,
+                            "retransmit data"
+                        );
+                        self.trace.lost(&packet, RETRANSMISSION_TIMEOUT_EXPIRED);
+                        segment.sent = clock::now();
+                        segment.retransmitted = true;
+                        self.socket.send(packet).await?;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Receives a packet, probing the client if it stays idle for too long
+    async fn receive_alive(
+        &mut self,
+        seq: Seq,
+        ack: Ack,
+    ) -> Result<Option<Packet>> {
+        let keepalive = match self.keepalive {
+            Some(keepalive) => keepalive,
+            None => return Ok(Some(self.source.receive().await)),
+        };
+        let receive = clock::timeout(keepalive.idle, self.source.receive());
+        if let Some(packet) = receive.await {
+            return Ok(Some(packet));
+        }
+        for _ in 0..keepalive.probes {
+            debug!("keep-alive probe");
+            self.socket.send(self.header.ack(seq - 1, ack)).await?;
+            let receive =
+                clock::timeout(keepalive.interval, self.source.receive());
+            if let Some(packet) = receive.await {
+                return Ok(Some(packet));
+            }
+        }
+        bail!("Connection timed out")
+    }
+
+    /// Acknowledges the FIN of the client, which acknowledged the FIN sent
+    /// at `seq` already, then waits for [`TIME_WAIT`] to pass without it
+    /// coming again
+    async fn linger(&mut self, seq: Seq, ack: Ack) -> Result<()> {
+        self.socket.send(self.header.ack(seq, ack)).await?;
+        while let Some(packet) =
+            clock::timeout(TIME_WAIT, self.source.receive()).await
+        {
+            if packet.fin() {
+                debug!(ack = ack.0, "acknowledge FIN again");
+                self.trace.stats.duplicate();
+                self.socket.send(self.header.ack(seq, ack)).await?;
+            }
+        }
+        Ok(())
     }
 
     async fn terminate_connection(&mut self, seq: Seq, ack: Ack) -> Result<()> {
+        let tag = match &mut self.cipher {
+            Some(cipher) => cipher.seal(&[])?,
+            None => Vec::new(),
+        };
+        let next_seq = seq + u32::try_from(tag.len())? + 1;
+        let mut retransmitted = false;
         loop {
-            self.socket.send(self.header.fin_ack(seq, ack + 1)).await?;
-            if let Some(packet) = self.source.receive().await {
-                if let Some(new_ack) = packet.ack(seq + 1) {
-                    if new_ack.0 == ack.0 + 1 {
-                        break Ok(());
+            let fin_ack = self.header.fin_ack(seq, ack, &tag);
+            let sent = clock::now();
+            self.socket.send(fin_ack.clone()).await?;
+            let packet = self.source.receive().await;
+            if resets(&packet, ack) {
+                bail!("Connection reset");
+            }
+            if let Some(new_ack) = packet.ack(next_seq) {
+                if new_ack.0 == ack.0 {
+                    if !retransmitted {
+                        self.measure(clock::now() - sent);
                     }
+                    break Ok(());
                 }
             }
+            debug!(seq = seq.0, "retransmit FIN-ACK");
+            self.trace.stats.duplicate();
+            self.trace.lost(&fin_ack, UNEXPECTED_REPLY);
+            retransmitted = true;
         }
     }
 
+    /// Takes an RTT sample of a segment that was never retransmitted
+    fn measure(&mut self, rtt: Duration) {
+        let rtt = self.trace.stats.sample_rtt(rtt);
+        self.trace.qlog.metrics_updated(&rtt);
+    }
+
+    /// Span of the connection being in `name` state, nested in the span of
+    /// the connection
+    fn state(&self, name: &'static str) -> Span {
+        let span = info_span!("state", name);
+        span.in_scope(|| info!("state changed"));
+        self.trace.qlog.state_updated(name);
+        self.trace.stats.state(name);
+        span
+    }
+
     fn close(&self) -> Result<()> {
         Ok(self.emitter.send(Event::Close(self.header.dest))?)
     }
+}
+
+/// Data the sink sends back to the client, one segment in flight at a time
+struct Replies {
+    /// Sequence number of the segment in flight, or of the next one
+    seq: Seq,
+    /// `None` once every handle to reply is dropped or the replies ended
+    receiver: Option<UnboundedReceiver<Queued>>,
+    /// Data to send after the segment in flight
+    pending: VecDeque<u8>,
+    in_flight: Option<InFlight>,
+    backlog: Arc<Backlog>,
+    /// Ended with [`Reply::finish`], so that a FIN follows the data
+    ended: bool,
+    fin_sent: bool,
+    /// Whether the sink asked for the connection to be reset
+    reset: bool,
+}
+
+struct InFlight {
+    /// As it goes over the wire, sealed if the connection is encrypted
+    data: Vec<u8>,
+    /// Length of the data before encryption
+    length: usize,
+    fin: bool,
+    sent: clock::Instant,
+    retransmitted: bool,
+}
+
+impl Replies {
+    fn new(
+        seq: Seq,
+        receiver: UnboundedReceiver<Queued>,
+        backlog: Arc<Backlog>,
+    ) -> Self {
+        Self {
+            seq,
+            receiver: Some(receiver),
+            pending: VecDeque::new(),
+            in_flight: None,
+            backlog,
+            ended: false,
+            fin_sent: false,
+            reset: false,
+        }
+    }
+
+    /// Sequence number following everything sent so far
+    fn next_seq(&self) -> Seq {
+        let length = self
+            .in_flight
+            .as_ref()
+            .map_or(0, |segment| segment.data.len() + usize::from(segment.fin));
+        self.seq + length as u32
+    }
+
+    /// Takes up to a chunk of the data left to send
+    fn next_chunk(&mut self) -> Option<Vec<u8>> {
+        self.poll();
+        let length = self.pending.len().min(CHUNK_SIZE);
+        if length == 0 {
+            return None;
+        }
+        self.backlog.bytes.fetch_sub(length, Ordering::Relaxed);
+        self.backlog.drained.notify_waiters();
+        Some(self.pending.drain(..length).collect())
+    }
+
+    /// Whether everything was sent and acknowledged, the FIN too if the
+    /// replies were ended, with nothing more to come
+    fn done(&mut self) -> bool {
+        self.poll();
+        self.receiver.is_none()
+            && self.pending.is_empty()
+            && self.in_flight.is_none()
+            && (!self.ended || self.fin_sent)
+            && !self.reset
+    }
 
-    fn report(&self, message: impl Into<Cow<'static, str>>) {
-        let message: Cow<str> = message.into();
-        println!("[{:?}]: {}", self.header.dest, &*message);
+    /// Takes what the sink queued so far
+    fn poll(&mut self) {
+        while let Some(receiver) = &mut self.receiver {
+            match receiver.try_recv() {
+                Ok(queued) => {
+                    self.take(Some(queued));
+                }
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.receiver = None,
+            }
+        }
+    }
+
+    /// Takes what the sink queued, `None` once every handle is dropped.
+    /// Returns whether there is nothing more to come.
+    fn take(&mut self, queued: Option<Queued>) -> bool {
+        match queued {
+            Some(Queued::Data(data)) => {
+                self.pending.extend(data);
+                return false;
+            }
+            Some(Queued::End) => self.ended = true,
+            Some(Queued::Reset) => self.reset = true,
+            None => {}
+        }
+        self.receiver = None;
+        true
+    }
+
+    async fn recv(
+        receiver: &mut Option<UnboundedReceiver<Queued>>,
+    ) -> Option<Queued> {
+        match receiver {
+            Some(receiver) => receiver.recv().await,
+            None => future::pending().await,
+        }
+    }
+}
+
+/// Everything recording what happens on a connection
+#[derive(Clone)]
+struct Trace {
+    qlog: Arc<Qlog>,
+    stats: Stats,
+    metrics: Arc<Metrics>,
+}
+
+impl Trace {
+    /// `packet` is deemed lost and about to be sent again
+    fn lost(&self, packet: &Packet, trigger: &str) {
+        self.qlog.packet_lost(packet, trigger);
+        self.stats.retransmitted();
+        self.metrics.retransmitted();
     }
 }
 
-struct Source(UnboundedReceiver<Packet>);
+struct Source(UnboundedReceiver<Packet>, Trace);
 
 impl Source {
-    async fn receive(&mut self) -> Option<Packet> {
+    async fn receive(&mut self) -> Packet {
         let packet = self.0.recv().await.unwrap();
-        if packet.check_sum() {
-            Some(packet)
-        } else {
-            None
-        }
+        self.1.qlog.packet_received(&packet);
+        self.1.stats.received(&packet);
+        packet
     }
 }
 
-struct ConnSocket(Socket, SocketAddr);
+struct ConnSocket<T>(Socket<T>, SocketAddr, Trace);
 
-impl ConnSocket {
+impl<T: DatagramTransport> ConnSocket<T> {
     async fn send(&self, packet: Packet) -> Result<()> {
+        self.2.qlog.packet_sent(&packet);
+        self.2.stats.sent(&packet);
         self.0.send_to(packet, self.1).await
     }
 }
@@ -218,14 +1180,17 @@ struct Header {
 }
 
 impl Header {
-    fn from_udp(socket: &Socket, address: SocketAddr) -> Result<Self> {
+    fn from_socket<T: DatagramTransport>(
+        socket: &Socket<T>,
+        address: SocketAddr,
+    ) -> Result<Self> {
         Ok(Self {
-            source: socket.0.local_addr()?,
+            source: socket.transport.local_addr()?,
             dest: address,
         })
     }
 
-    fn syn_ack(&self, seq: Seq, ack: Ack) -> Packet {
+    fn syn_ack(&self, seq: Seq, ack: Ack, data: &[u8]) -> Packet {
         Packet::from(PseudoPacket {
             source: self.source,
             dest: self.dest,
@@ -233,6 +1198,7 @@ impl Header {
             extra: PacketExtra {
                 ack,
                 flags: Flags::default().flip_syn().flip_ack(),
+                data: data.into(),
                 ..Default::default()
             },
         })
@@ -251,7 +1217,51 @@ impl Header {
         })
     }
 
-    fn fin_ack(&self, seq: Seq, ack: Ack) -> Packet {
+    fn data(&self, seq: Seq, ack: Ack, data: &[u8]) -> Packet {
+        Packet::from(PseudoPacket {
+            source: self.source,
+            dest: self.dest,
+            seq,
+            extra: PacketExtra {
+                ack,
+                flags: Flags::default().flip_ack(),
+                data: data.into(),
+                ..Default::default()
+            },
+        })
+    }
+
+    fn rst(&self, ack: Ack) -> Packet {
+        Packet::from(PseudoPacket {
+            source: self.source,
+            dest: self.dest,
+            seq: Seq(0),
+            extra: PacketExtra {
+                ack,
+                flags: Flags::default().flip_rst().flip_ack(),
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Reset answering `packet`, acknowledging everything it carries
+    fn reset(&self, packet: &Packet) -> Result<Packet> {
+        let length = u32::try_from(packet.data().len())?
+            + u32::from(packet.flags().is_syn())
+            + u32::from(packet.fin());
+        Ok(self.rst(packet.seq() + length))
+    }
+
+    /// Segment of the replies at `seq`
+    fn segment(&self, seq: Seq, ack: Ack, segment: &InFlight) -> Packet {
+        if segment.fin {
+            self.fin_ack(seq, ack, &segment.data)
+        } else {
+            self.data(seq, ack, &segment.data)
+        }
+    }
+
+    fn fin_ack(&self, seq: Seq, ack: Ack, data: &[u8]) -> Packet {
         Packet::from(PseudoPacket {
             source: self.source,
             dest: self.dest,
@@ -259,6 +1269,7 @@ impl Header {
             extra: PacketExtra {
                 ack,
                 flags: Flags::default().flip_fin().flip_ack(),
+                data: data.into(),
                 ..Default::default()
             },
         })
@@ -0,0 +1,282 @@
+//! Simulated network and transfers in virtual time, for tests.
+//!
+//! Only built with the `sim` feature, which enables the test utilities of
+//! tokio the virtual clock relies on.
+
+use std::{
+    collections::HashMap,
+    io::{self, Cursor},
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{
+    runtime,
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+};
+
+use crate::{
+    client::{read_chunks_async, run_client, ClientConfig},
+    clock,
+    isn::Rfc6528,
+    server::{run_server, ServerConfig},
+    transport::DatagramTransport,
+};
+
+/// First port handed out to sockets bound to port zero
+const EPHEMERAL_PORTS: u16 = 49152;
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Impairments applied to every datagram crossing a simulated network,
+/// modelled after netem.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    /// Probability of a datagram being dropped
+    pub loss: f64,
+    /// Base one-way delay
+    pub delay: Duration,
+    /// Upper bound of a uniformly distributed delay added to `delay`
+    pub jitter: Duration,
+    /// Probability of a datagram skipping the delay, overtaking the others
+    pub reorder: f64,
+    /// Probability of a datagram being delivered twice
+    pub duplicate: f64,
+    /// Probability of a single random bit of a datagram being flipped
+    pub corrupt: f64,
+}
+
+impl LinkConfig {
+    /// Fails unless every probability is within `0.0..=1.0`
+    fn check(&self) -> Result<()> {
+        let probabilities = [
+            ("loss", self.loss),
+            ("reorder", self.reorder),
+            ("duplicate", self.duplicate),
+            ("corrupt", self.corrupt),
+        ];
+        for (name, probability) in probabilities {
+            if !(0.0..=1.0).contains(&probability) {
+                bail!("Probability of {} out of range: {}", name, probability);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// In-memory network of datagram sockets, reproducible for a given seed.
+#[derive(Clone)]
+pub struct Network(Arc<Mutex<NetworkState>>);
+
+struct NetworkState {
+    config: LinkConfig,
+    rng: StdRng,
+    sockets: HashMap<SocketAddr, UnboundedSender<Datagram>>,
+    next_port: u16,
+}
+
+impl Network {
+    /// Fails if `config` is not a valid set of impairments
+    pub fn new(config: LinkConfig, seed: u64) -> Result<Self> {
+        config.check()?;
+        Ok(Self(Arc::new(Mutex::new(NetworkState {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            sockets: HashMap::new(),
+            next_port: EPHEMERAL_PORTS,
+        }))))
+    }
+
+    /// Binds a socket, picking a free port if the port of `address` is zero
+    pub fn bind(&self, address: SocketAddr) -> io::Result<SimSocket> {
+        let mut state = self.0.lock().unwrap();
+        let mut address = address;
+        if address.ip().is_unspecified() {
+            address.set_ip(IpAddr::V4(Ipv4Addr::LOCALHOST));
+        }
+        if address.port() == 0 {
+            loop {
+                address.set_port(state.next_port);
+                state.next_port = match state.next_port.checked_add(1) {
+                    Some(port) => port,
+                    None => EPHEMERAL_PORTS,
+                };
+                if !state.sockets.contains_key(&address) {
+                    break;
+                }
+            }
+        }
+        if state.sockets.contains_key(&address) {
+            return Err(io::ErrorKind::AddrInUse.into());
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        state.sockets.insert(address, tx);
+        Ok(SimSocket {
+            network: self.clone(),
+            address,
+            inbox: AsyncMutex::new(rx),
+        })
+    }
+
+    fn send(&self, data: &[u8], source: SocketAddr, dest: SocketAddr) {
+        let mut state = self.0.lock().unwrap();
+        let inbox = match state.sockets.get(&dest) {
+            Some(inbox) => inbox.clone(),
+            None => return,
+        };
+        let config = state.config.clone();
+        let rng = &mut state.rng;
+        if rng.gen_bool(config.loss) {
+            return;
+        }
+        let copies = if rng.gen_bool(config.duplicate) { 2 } else { 1 };
+        for _ in 0..copies {
+            let mut data = Vec::from(data);
+            if !data.is_empty() && rng.gen_bool(config.corrupt) {
+                let bit = rng.gen_range(0..data.len() * 8);
+                data[bit / 8] ^= 1 << (bit % 8);
+            }
+            let delay = if rng.gen_bool(config.reorder) {
+                Duration::ZERO
+            } else {
+                config.delay + config.jitter.mul_f64(rng.gen())
+            };
+            let inbox = inbox.clone();
+            tokio::spawn(async move {
+                clock::sleep(delay).await;
+                let _ = inbox.send((data, source));
+            });
+        }
+    }
+
+    fn unbind(&self, address: &SocketAddr) {
+        self.0.lock().unwrap().sockets.remove(address);
+    }
+}
+
+/// Datagram socket of a simulated [`Network`]
+pub struct SimSocket {
+    network: Network,
+    address: SocketAddr,
+    inbox: AsyncMutex<UnboundedReceiver<Datagram>>,
+}
+
+impl DatagramTransport for SimSocket {
+    async fn send_to(
+        &self,
+        buffer: &[u8],
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        self.network.send(buffer, self.address, target);
+        Ok(buffer.len())
+    }
+
+    async fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        let (data, source) = self
+            .inbox
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        let length = data.len().min(buffer.len());
+        buffer[..length].copy_from_slice(&data[..length]);
+        Ok((length, source))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.network.unbind(&self.address);
+    }
+}
+
+/// Transfer of a payload from a client to a server across a simulated link
+#[derive(Debug, Clone, Default)]
+pub struct Simulation {
+    pub link: LinkConfig,
+    /// Seed of the link impairments and of the initial sequence numbers
+    pub seed: u64,
+    pub payload: Vec<u8>,
+    /// Virtual time after which the transfer is given up, unlimited if zero
+    pub deadline: Duration,
+}
+
+/// Result of a completed [`Simulation`]
+#[derive(Debug)]
+pub struct Outcome {
+    /// Data delivered to the server
+    pub received: Vec<u8>,
+    /// Virtual time the transfer took
+    pub elapsed: Duration,
+}
+
+/// Runs `simulation` on a virtual clock, so that its duration does not
+/// depend on the timeouts involved. The same simulation always plays out
+/// the same way.
+///
+/// Starts a runtime of its own, hence must not be called from within one.
+pub fn simulate(simulation: Simulation) -> Result<Outcome> {
+    runtime::Builder::new_current_thread()
+        .enable_all()
+        .start_paused(true)
+        .build()?
+        .block_on(transfer(simulation))
+}
+
+async fn transfer(simulation: Simulation) -> Result<Outcome> {
+    let Simulation {
+        link,
+        seed,
+        payload,
+        deadline,
+    } = simulation;
+    let network = Network::new(link, seed)?;
+    let server = network.bind(SocketAddr::from(([10, 0, 0, 1], 7)))?;
+    let client = network.bind(SocketAddr::from(([10, 0, 0, 2], 0)))?;
+    let peer = server.local_addr()?;
+
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let server_config = ServerConfig {
+        isn: Some(Arc::new(Rfc6528::with_key((seed, 1)))),
+        sink: Some(Arc::new(move |_, data: Vec<u8>| {
+            sink.lock().unwrap().extend(data)
+        })),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, server_config));
+    let client_config = ClientConfig {
+        isn: Some(Arc::new(Rfc6528::with_key((seed, 2)))),
+        ..Default::default()
+    };
+
+    let start = clock::now();
+    let chunks = read_chunks_async(Cursor::new(payload));
+    let transfer = run_client(client, peer, chunks, client_config);
+    let result = if deadline.is_zero() {
+        Some(transfer.await)
+    } else {
+        clock::timeout(deadline, transfer).await
+    };
+    server.abort();
+    result.ok_or(anyhow!("Transfer did not complete in time"))??;
+    let received = received.lock().unwrap().clone();
+    Ok(Outcome {
+        received,
+        elapsed: start.elapsed(),
+    })
+}
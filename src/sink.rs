@@ -0,0 +1,207 @@
+//! Destinations the server can hand received data to.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use tokio::sync::Notify;
+use tracing::{warn, Instrument};
+
+use crate::{
+    client::{read_chunks, Chunks},
+    server::{Reply, Sink},
+    stats::ConnectionStats,
+};
+
+/// Writes the data of every connection to standard output as it arrives,
+/// byte for byte
+pub struct Stdout;
+
+impl Sink for Stdout {
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        let mut stdout = io::stdout().lock();
+        if let Err(err) = stdout.write_all(&data).and_then(|_| stdout.flush()) {
+            warn!(%peer, %err, "cannot write to stdout");
+        }
+    }
+}
+
+/// Drops every byte, to measure the transport alone
+pub struct Discard;
+
+impl Sink for Discard {
+    fn receive(&self, _peer: SocketAddr, _data: Vec<u8>) {}
+}
+
+/// Sends the data of every connection back to its peer
+#[derive(Default)]
+pub struct Echo(Mutex<HashMap<SocketAddr, Reply>>);
+
+impl Sink for Echo {
+    fn open(&self, peer: SocketAddr, reply: Reply) {
+        self.0.lock().unwrap().insert(peer, reply);
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        if let Some(reply) = self.0.lock().unwrap().get(&peer) {
+            reply.send(data);
+        }
+    }
+
+    fn finish(&self, peer: SocketAddr) {
+        self.0.lock().unwrap().remove(&peer);
+    }
+
+    fn close(&self, peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+        self.0.lock().unwrap().remove(&peer);
+    }
+}
+
+/// Writes the data of every connection into a file of its own, named
+/// after the time it was established and the peer
+pub struct Directory {
+    path: PathBuf,
+    files: Mutex<HashMap<SocketAddr, BufWriter<File>>>,
+}
+
+impl Directory {
+    /// Writes into `path`, created if missing
+    pub fn create(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        fs::create_dir_all(&path)?;
+        Ok(Self {
+            path,
+            files: Default::default(),
+        })
+    }
+}
+
+impl Sink for Directory {
+    fn open(&self, peer: SocketAddr, _reply: Reply) {
+        let established = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let path = self.path.join(format!("{}-{}", established, peer));
+        match File::create(&path) {
+            Ok(file) => {
+                let file = BufWriter::new(file);
+                self.files.lock().unwrap().insert(peer, file);
+            }
+            Err(err) => {
+                warn!(%peer, path = %path.display(), %err, "cannot create file")
+            }
+        }
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        let mut files = self.files.lock().unwrap();
+        if let Some(file) = files.get_mut(&peer) {
+            if let Err(err) = file.write_all(&data) {
+                warn!(%peer, %err, "cannot write file");
+                files.remove(&peer);
+            }
+        }
+    }
+
+    fn finish(&self, peer: SocketAddr) {
+        if let Some(mut file) = self.files.lock().unwrap().remove(&peer) {
+            if let Err(err) = file.flush() {
+                warn!(%peer, %err, "cannot write file");
+            }
+        }
+    }
+
+    fn close(&self, peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+        self.finish(peer);
+    }
+}
+
+/// Talks with a single peer like `nc`: standard input goes to it while its
+/// data goes to standard output. The FIN goes out as soon as standard
+/// input ends, the connection is over once the peer has sent its FIN too.
+pub struct Netcat {
+    peer: Mutex<Option<SocketAddr>>,
+    /// Taken by the connection with the peer
+    input: Mutex<Option<Chunks>>,
+    output: Box<dyn Sink>,
+    closed: Notify,
+}
+
+impl Default for Netcat {
+    fn default() -> Self {
+        Self::new(read_chunks(io::stdin()), Stdout)
+    }
+}
+
+impl Netcat {
+    /// Sends `input` to the peer and hands its data to `output` instead
+    pub fn new(input: Chunks, output: impl Sink + 'static) -> Self {
+        Self {
+            peer: Mutex::default(),
+            input: Mutex::new(Some(input)),
+            output: Box::new(output),
+            closed: Notify::new(),
+        }
+    }
+
+    /// Waits for the connection with the peer to be over
+    pub async fn closed(&self) {
+        self.closed.notified().await
+    }
+
+    fn talks_with(&self, peer: SocketAddr) -> bool {
+        *self.peer.lock().unwrap() == Some(peer)
+    }
+}
+
+impl Sink for Netcat {
+    fn open(&self, peer: SocketAddr, reply: Reply) {
+        let mut current = self.peer.lock().unwrap();
+        if let Some(current) = *current {
+            warn!(%peer, %current, "already talking with another peer");
+            return;
+        }
+        *current = Some(peer);
+        let mut input = match self.input.lock().unwrap().take() {
+            Some(input) => input,
+            None => return,
+        };
+        let replies = async move {
+            while let Some(chunk) = input.recv().await {
+                let data = match chunk {
+                    Ok(data) => data,
+                    Err(err) => {
+                        warn!(%peer, %err, "cannot read input");
+                        reply.reset();
+                        return;
+                    }
+                };
+                reply.ready().await;
+                if !reply.send(data) {
+                    return;
+                }
+            }
+            reply.finish();
+        };
+        tokio::spawn(replies.in_current_span());
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        if self.talks_with(peer) {
+            self.output.receive(peer, data)
+        }
+    }
+
+    fn close(&self, peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+        if self.talks_with(peer) {
+            self.closed.notify_one();
+        }
+    }
+}
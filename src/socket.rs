@@ -1,74 +1,134 @@
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use anyhow::Result;
+use tracing::{debug, trace, warn};
 
-use crate::packet::Packet;
+use crate::{
+    auth::Psk, metrics::Metrics, packet::Packet, pcap::Pcap,
+    transport::DatagramTransport,
+};
 
 pub const MAX_PACKET_SIZE: usize = 2048;
 pub const CHUNK_SIZE: usize = 1024;
+/// How long either side waits for a segment in flight to be acknowledged
+pub const RETRANSMISSION_TIMEOUT: Duration = Duration::from_millis(250);
 
-pub struct PacketSocket<T>(pub T);
+/// Socket sending and receiving whole packets, authenticated by an optional
+/// pre-shared key, recorded into an optional capture file and counted into
+/// optional metrics
+pub struct PacketSocket<T> {
+    pub transport: T,
+    pub psk: Option<Psk>,
+    pub pcap: Option<Pcap>,
+    pub metrics: Option<Arc<Metrics>>,
+    /// Number of unauthenticated packets dropped so far
+    dropped: AtomicU64,
+}
 
-impl PacketSocket<std::net::UdpSocket> {
-    pub fn new(socket: std::net::UdpSocket) -> Self {
-        socket
-            .set_read_timeout(Some(Duration::from_millis(250)))
-            .unwrap();
-        Self(socket)
+impl<T> PacketSocket<T> {
+    pub fn new(
+        transport: T,
+        psk: Option<Psk>,
+        pcap: Option<Pcap>,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Self {
+        Self {
+            transport,
+            psk,
+            pcap,
+            metrics,
+            dropped: AtomicU64::new(0),
+        }
     }
 
-    pub fn send(&self, packet: Packet) -> Result<()> {
-        println!("sending packet {:?}", packet);
-        let packet = packet.into_bytes();
-        assert!(packet.len() == self.0.send(&packet)?);
-        println!("sent packet");
-        Ok(())
+    fn sign(&self, mut packet: Packet) -> Packet {
+        if let Some(psk) = &self.psk {
+            psk.sign(&mut packet);
+            packet.update_checksum();
+        }
+        packet
     }
 
-    pub fn recv(&self) -> Result<Option<Packet>> {
-        println!("receiving packet");
-        let mut buffer = Vec::new();
-        buffer.resize(MAX_PACKET_SIZE, 0);
-        let packet_size = match self.0.recv(&mut buffer) {
-            Ok(packet_size) => packet_size,
-            Err(err) => match err.kind() {
-                std::io::ErrorKind::TimedOut => return Ok(None),
-                _ => return Err(err.into()),
-            },
-        };
-        buffer.truncate(packet_size);
-        println!("received buffer of length {}", packet_size);
-        let packet = Packet::from_bytes(buffer)?;
-        println!("received packet {:?}", packet);
-        Ok(if packet.check_sum() {
-            Some(packet)
-        } else {
-            None
-        })
+    fn authentic(&self, packet: &Packet) -> bool {
+        self.psk.as_ref().is_none_or(|psk| psk.verify(packet))
     }
 }
 
-impl PacketSocket<tokio::net::UdpSocket> {
+impl<T: DatagramTransport> PacketSocket<T> {
     pub async fn send_to(
         &self,
         packet: Packet,
         address: SocketAddr,
     ) -> Result<()> {
-        println!("{:?}: sending packet {:?}", address, packet);
+        let local = self.transport.local_addr()?;
+        let packet = self.sign(packet);
+        trace!(%address, ?packet, "send");
         let packet = packet.into_bytes();
-        assert!(packet.len() == self.0.send_to(&packet, address).await?);
-        println!("{:?}: sent packet", address);
+        if let Some(pcap) = &self.pcap {
+            pcap.record(local, address, &packet);
+        }
+        assert!(
+            packet.len() == self.transport.send_to(&packet, address).await?
+        );
+        if let Some(metrics) = &self.metrics {
+            metrics.sent(packet.len());
+        }
         Ok(())
     }
 
+    /// Receives the next packet, skipping malformed, broken and
+    /// unauthenticated ones
     pub async fn recv_from(&self) -> Result<(Packet, SocketAddr)> {
-        println!("receiving packet");
-        let mut buffer = Vec::new();
-        buffer.resize(MAX_PACKET_SIZE, 0);
-        let (packet_size, address) = self.0.recv_from(&mut buffer).await?;
-        buffer.truncate(packet_size);
-        let packet = Packet::from_bytes(buffer)?;
-        println!("{:?}: received packet {:?}", address, packet);
-        Ok((packet, address))
+        loop {
+            let mut buffer = vec![0; MAX_PACKET_SIZE];
+            let (packet_size, address) =
+                self.transport.recv_from(&mut buffer).await?;
+            buffer.truncate(packet_size);
+            if let Some(pcap) = &self.pcap {
+                pcap.record(address, self.transport.local_addr()?, &buffer);
+            }
+            let metrics = self.metrics.as_deref();
+            if let Some(metrics) = metrics {
+                metrics.received(packet_size);
+            }
+            let packet = match Packet::from_bytes(buffer) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    debug!(%address, %err, "dropped malformed packet");
+                    if let Some(metrics) = metrics {
+                        metrics.malformed();
+                    }
+                    continue;
+                }
+            };
+            trace!(%address, ?packet, "receive");
+            if !packet.verify_checksum() {
+                debug!(%address, ?packet, "dropped broken packet");
+                if let Some(metrics) = metrics {
+                    metrics.checksum_failed();
+                }
+            } else if !self.authentic(&packet) {
+                if let Some(metrics) = metrics {
+                    metrics.unauthenticated();
+                }
+                let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+                // the key differs on the other side, or someone forges
+                // segments, so warn without flooding the log
+                if dropped.is_power_of_two() {
+                    warn!(%address, dropped, "dropped unauthenticated packet");
+                } else {
+                    debug!(%address, ?packet, "dropped unauthenticated packet");
+                }
+            } else {
+                break Ok((packet, address));
+            }
+        }
     }
 }
@@ -0,0 +1,312 @@
+//! SOCKS5 proxy carried over udptcp (RFC 1928, CONNECT only).
+//!
+//! The client negotiates with local applications and opens a connection
+//! per request, its first bytes being the requested destination encoded
+//! as in SOCKS5: address type, address and port. The server dials the
+//! destination and answers with a single SOCKS5 reply code before any
+//! data, which the client passes on. Either side closing its write half
+//! ends the matching direction, and a failure code ends the replies.
+
+use std::{
+    convert::TryFrom,
+    fmt::{self, Display},
+    io::Cursor,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    net::{tcp::OwnedWriteHalf, TcpListener, TcpStream},
+    sync::mpsc::UnboundedReceiver,
+};
+use tracing::{debug, info, warn};
+
+use crate::{
+    client::{connect_udp, read_chunks_async, run_client, ClientConfig},
+    server::{Reply, Sink},
+    socket::CHUNK_SIZE,
+    stats::ConnectionStats,
+    tunnel::{shuttle, Tunnels},
+};
+
+const VERSION: u8 = 5;
+const NO_AUTHENTICATION: u8 = 0;
+const NO_ACCEPTABLE_METHODS: u8 = 0xff;
+const CONNECT: u8 = 1;
+
+const IPV4: u8 = 1;
+const DOMAIN: u8 = 3;
+const IPV6: u8 = 4;
+
+/// Reply codes
+const SUCCEEDED: u8 = 0;
+const GENERAL_FAILURE: u8 = 1;
+const NETWORK_UNREACHABLE: u8 = 3;
+const HOST_UNREACHABLE: u8 = 4;
+const CONNECTION_REFUSED: u8 = 5;
+const COMMAND_NOT_SUPPORTED: u8 = 7;
+const ADDRESS_TYPE_NOT_SUPPORTED: u8 = 8;
+
+/// Serves SOCKS5 to every application connecting to `listener`, carrying
+/// each request to the server at `server` over a connection set up by
+/// `config`
+pub async fn proxy(
+    listener: TcpListener,
+    server: SocketAddr,
+    config: impl Fn() -> ClientConfig,
+) -> Result<()> {
+    info!(address = %listener.local_addr()?, %server, "serving SOCKS5");
+    loop {
+        let (stream, address) = listener.accept().await?;
+        debug!(%address, "accepted");
+        let config = config();
+        tokio::spawn(async move {
+            if let Err(err) = carry(stream, server, config).await {
+                warn!(%address, %err, "SOCKS5 request failed");
+            }
+        });
+    }
+}
+
+async fn carry(
+    stream: TcpStream,
+    server: SocketAddr,
+    config: ClientConfig,
+) -> Result<()> {
+    let (mut reader, mut writer) = stream.into_split();
+    let target = match negotiate(&mut reader, &mut writer).await? {
+        Some(target) => target,
+        None => return Ok(()),
+    };
+    if let Some((target, _)) = Target::parse(&target)? {
+        debug!(%target, "connecting");
+    }
+    let (socket, peer) = connect_udp(server).await?;
+    let (output, replies) = io::duplex(CHUNK_SIZE);
+    let config = ClientConfig {
+        output: Some(Box::new(output)),
+        ..config
+    };
+    let chunks = read_chunks_async(Cursor::new(target).chain(reader));
+    let client = run_client(socket, peer, chunks, config);
+    let (client, relay) = tokio::join!(client, relay(replies, writer));
+    client.and(relay)
+}
+
+/// Reads the greeting and the request of an application, answering it
+/// right away unless it asks to connect somewhere, in which case the
+/// destination is returned in its encoded form
+async fn negotiate(
+    reader: &mut (impl AsyncRead + Unpin),
+    writer: &mut OwnedWriteHalf,
+) -> Result<Option<Vec<u8>>> {
+    let mut greeting = [0; 2];
+    reader.read_exact(&mut greeting).await?;
+    if greeting[0] != VERSION {
+        bail!("Unsupported SOCKS version {}", greeting[0]);
+    }
+    let mut methods = vec![0; greeting[1] as usize];
+    reader.read_exact(&mut methods).await?;
+    if !methods.contains(&NO_AUTHENTICATION) {
+        writer.write_all(&[VERSION, NO_ACCEPTABLE_METHODS]).await?;
+        return Ok(None);
+    }
+    writer.write_all(&[VERSION, NO_AUTHENTICATION]).await?;
+
+    let mut request = [0; 4];
+    reader.read_exact(&mut request).await?;
+    let [version, command, _, kind] = request;
+    if version != VERSION {
+        bail!("Unsupported SOCKS version {}", version);
+    }
+    let length = match kind {
+        IPV4 => 4,
+        IPV6 => 16,
+        DOMAIN => reader.read_u8().await? as usize,
+        _ => {
+            writer.write_all(&reply(ADDRESS_TYPE_NOT_SUPPORTED)).await?;
+            return Ok(None);
+        }
+    };
+    let mut target = vec![kind];
+    if kind == DOMAIN {
+        target.push(length as u8);
+    }
+    let start = target.len();
+    target.resize(start + length + 2, 0);
+    reader.read_exact(&mut target[start..]).await?;
+    if command != CONNECT {
+        writer.write_all(&reply(COMMAND_NOT_SUPPORTED)).await?;
+        return Ok(None);
+    }
+    Ok(Some(target))
+}
+
+/// Passes the reply code of the server on to the application, then the
+/// data that follows it. Whatever the application cannot take is drained
+/// so that the connection still finishes.
+async fn relay(
+    mut replies: impl AsyncRead + Unpin,
+    mut writer: OwnedWriteHalf,
+) -> Result<()> {
+    let code = replies.read_u8().await.unwrap_or(GENERAL_FAILURE);
+    let relayed = async {
+        writer.write_all(&reply(code)).await?;
+        if code == SUCCEEDED {
+            io::copy(&mut replies, &mut writer).await?;
+        }
+        writer.shutdown().await
+    };
+    let relayed = relayed.await;
+    io::copy(&mut replies, &mut io::sink()).await?;
+    Ok(relayed?)
+}
+
+/// Reply to a request, with an unspecified bound address
+fn reply(code: u8) -> [u8; 10] {
+    [VERSION, code, 0, IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+/// Destination of a CONNECT request
+#[derive(Debug, Clone, PartialEq)]
+enum Target {
+    Address(SocketAddr),
+    Domain(String, u16),
+}
+
+impl Target {
+    /// Decodes a destination from the start of `bytes`, returning it with
+    /// its encoded length, or `None` if more bytes are needed
+    fn parse(bytes: &[u8]) -> Result<Option<(Self, usize)>> {
+        let (kind, rest) = match bytes.split_first() {
+            Some(split) => split,
+            None => return Ok(None),
+        };
+        let (length, start) = match *kind {
+            IPV4 => (4, 1),
+            IPV6 => (16, 1),
+            DOMAIN => match rest.first() {
+                Some(length) => (*length as usize, 2),
+                None => return Ok(None),
+            },
+            kind => bail!("Unknown address type {}", kind),
+        };
+        let end = start + length + 2;
+        if bytes.len() < end {
+            return Ok(None);
+        }
+        let address = &bytes[start..end - 2];
+        let port = u16::from_be_bytes([bytes[end - 2], bytes[end - 1]]);
+        let target = match *kind {
+            IPV4 => {
+                let ip = <[u8; 4]>::try_from(address)?;
+                Target::Address((Ipv4Addr::from(ip), port).into())
+            }
+            IPV6 => {
+                let ip = <[u8; 16]>::try_from(address)?;
+                Target::Address((Ipv6Addr::from(ip), port).into())
+            }
+            _ => Target::Domain(String::from_utf8(address.to_vec())?, port),
+        };
+        Ok(Some((target, end)))
+    }
+
+    async fn connect(&self) -> io::Result<TcpStream> {
+        match self {
+            Target::Address(address) => TcpStream::connect(address).await,
+            Target::Domain(host, port) => {
+                TcpStream::connect((host.as_str(), *port)).await
+            }
+        }
+    }
+}
+
+impl Display for Target {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Target::Address(address) => address.fmt(f),
+            Target::Domain(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+/// Reply code telling why connecting failed
+fn failure(err: &io::Error) -> u8 {
+    match err.kind() {
+        io::ErrorKind::ConnectionRefused => CONNECTION_REFUSED,
+        io::ErrorKind::NetworkUnreachable => NETWORK_UNREACHABLE,
+        io::ErrorKind::HostUnreachable | io::ErrorKind::TimedOut => {
+            HOST_UNREACHABLE
+        }
+        _ => GENERAL_FAILURE,
+    }
+}
+
+/// Sink connecting every connection to the destination its first bytes
+/// ask for.
+///
+/// Anyone able to connect can reach whatever the server can, so it is
+/// meant to be combined with a pre-shared key or a Noise peer key.
+#[derive(Default)]
+pub struct Dialer(Tunnels);
+
+impl Sink for Dialer {
+    fn open(&self, peer: SocketAddr, reply: Reply) {
+        self.0
+            .open(peer, move |upstream| dial(peer, upstream, reply));
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        self.0.receive(peer, data)
+    }
+
+    fn finish(&self, peer: SocketAddr) {
+        self.0.finish(peer)
+    }
+
+    fn close(&self, peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+        self.0.close(peer)
+    }
+}
+
+async fn dial(
+    peer: SocketAddr,
+    mut upstream: UnboundedReceiver<Vec<u8>>,
+    reply: Reply,
+) {
+    let mut received = Vec::new();
+    let (target, length) = loop {
+        match Target::parse(&received) {
+            Ok(Some(target)) => break target,
+            Ok(None) => match upstream.recv().await {
+                Some(data) => received.extend(data),
+                None => return,
+            },
+            Err(err) => {
+                warn!(%peer, %err, "malformed destination");
+                reply.send(vec![GENERAL_FAILURE]);
+                reply.finish();
+                return;
+            }
+        }
+    };
+    let stream = match target.connect().await {
+        Ok(stream) => stream,
+        Err(err) => {
+            warn!(%peer, %target, %err, "cannot connect");
+            reply.send(vec![failure(&err)]);
+            reply.finish();
+            return;
+        }
+    };
+    info!(%peer, %target, "connected");
+    reply.send(vec![SUCCEEDED]);
+    let (reader, mut writer) = stream.into_split();
+    if let Err(err) = writer.write_all(&received[length..]).await {
+        warn!(%peer, %target, %err, "tunnel failed");
+        reply.reset();
+        return;
+    }
+    shuttle(peer, upstream, writer, reader, reply).await
+}
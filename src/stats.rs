@@ -0,0 +1,190 @@
+//! Statistics of a connection, in the spirit of `TCP_INFO`.
+
+use std::{
+    fmt::{self, Display},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::{
+    clock,
+    packet::{Packet, WindowSize},
+    rtt::RttEstimator,
+    socket::CHUNK_SIZE,
+};
+
+/// Snapshot of how a connection is doing
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectionStats {
+    /// Current state, or the one the connection failed in
+    pub state: &'static str,
+    /// Time since the connection started, up to its end if it is over
+    pub duration: Duration,
+    /// Payload bytes as they went over the wire, encrypted if so
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    /// Bytes of data the peer acknowledged, before any encryption
+    pub bytes_acked: u64,
+    pub segments_sent: u64,
+    pub segments_received: u64,
+    pub retransmissions: u64,
+    /// Segments received again, or acknowledging nothing new
+    pub duplicates: u64,
+    /// Smoothed round-trip time, `None` before the first sample
+    pub srtt: Option<Duration>,
+    pub rttvar: Duration,
+    /// Retransmission timeout, `None` if the side only retransmits when
+    /// the peer repeats itself
+    pub rto: Option<Duration>,
+    /// Congestion window in bytes, always a single segment
+    pub cwnd: usize,
+    /// Slow start threshold, `None` as there is no slow start
+    pub ssthresh: Option<usize>,
+    /// Receive window advertised to the peer
+    pub rwnd: u16,
+}
+
+impl Display for ConnectionStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} for {:?}, sent {} B in {} segments, received {} B in {} \
+            segments, {} retransmissions, {} duplicates",
+            self.state,
+            self.duration,
+            self.bytes_sent,
+            self.segments_sent,
+            self.bytes_received,
+            self.segments_received,
+            self.retransmissions,
+            self.duplicates,
+        )?;
+        if let Some(srtt) = self.srtt {
+            write!(f, ", srtt {:?} rttvar {:?}", srtt, self.rttvar)?;
+        }
+        if let Some(rto) = self.rto {
+            write!(f, ", rto {:?}", rto)?;
+        }
+        write!(f, ", cwnd {} B", self.cwnd)?;
+        if let Some(ssthresh) = self.ssthresh {
+            write!(f, ", ssthresh {} B", ssthresh)?;
+        }
+        write!(f, ", rwnd {} B", self.rwnd)
+    }
+}
+
+/// Live statistics of a connection, shared by the connection and whoever
+/// watches it
+#[derive(Clone, Default)]
+pub struct Stats(Arc<Mutex<Recorder>>);
+
+#[derive(Default)]
+struct Recorder {
+    started: Option<clock::Instant>,
+    finished: Option<clock::Instant>,
+    state: &'static str,
+    bytes_sent: u64,
+    bytes_received: u64,
+    bytes_acked: u64,
+    segments_sent: u64,
+    segments_received: u64,
+    retransmissions: u64,
+    duplicates: u64,
+    rtt: RttEstimator,
+    /// Every RTT sample since they were last taken, if asked to keep them
+    rtt_samples: Option<Vec<Duration>>,
+    rto: Option<Duration>,
+}
+
+impl Stats {
+    pub fn snapshot(&self) -> ConnectionStats {
+        let recorder = self.0.lock().unwrap();
+        let duration = match recorder.started {
+            Some(started) => {
+                recorder.finished.unwrap_or_else(clock::now) - started
+            }
+            None => Duration::ZERO,
+        };
+        ConnectionStats {
+            state: recorder.state,
+            duration,
+            bytes_sent: recorder.bytes_sent,
+            bytes_received: recorder.bytes_received,
+            bytes_acked: recorder.bytes_acked,
+            segments_sent: recorder.segments_sent,
+            segments_received: recorder.segments_received,
+            retransmissions: recorder.retransmissions,
+            duplicates: recorder.duplicates,
+            srtt: recorder.rtt.smoothed(),
+            rttvar: recorder.rtt.variance(),
+            rto: recorder.rto,
+            cwnd: CHUNK_SIZE,
+            ssthresh: None,
+            rwnd: WindowSize::default().0,
+        }
+    }
+
+    /// Keeps every RTT sample from now on, until taken
+    pub fn keep_rtt_samples(&self) {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.rtt_samples.get_or_insert_with(Vec::new);
+    }
+
+    /// RTT samples since the last call, in the order they were taken
+    pub fn take_rtt_samples(&self) -> Vec<Duration> {
+        let mut recorder = self.0.lock().unwrap();
+        recorder
+            .rtt_samples
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub(crate) fn start(&self, rto: Option<Duration>) {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.started = Some(clock::now());
+        recorder.rto = rto;
+    }
+
+    pub(crate) fn finish(&self) {
+        self.0.lock().unwrap().finished = Some(clock::now());
+    }
+
+    pub(crate) fn state(&self, state: &'static str) {
+        self.0.lock().unwrap().state = state;
+    }
+
+    pub(crate) fn sent(&self, packet: &Packet) {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.segments_sent += 1;
+        recorder.bytes_sent += packet.data().len() as u64;
+    }
+
+    pub(crate) fn received(&self, packet: &Packet) {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.segments_received += 1;
+        recorder.bytes_received += packet.data().len() as u64;
+    }
+
+    pub(crate) fn acked(&self, length: usize) {
+        self.0.lock().unwrap().bytes_acked += length as u64;
+    }
+
+    pub(crate) fn retransmitted(&self) {
+        self.0.lock().unwrap().retransmissions += 1;
+    }
+
+    pub(crate) fn duplicate(&self) {
+        self.0.lock().unwrap().duplicates += 1;
+    }
+
+    /// Takes an RTT sample, returning the updated estimate
+    pub(crate) fn sample_rtt(&self, rtt: Duration) -> RttEstimator {
+        let mut recorder = self.0.lock().unwrap();
+        recorder.rtt.sample(rtt);
+        if let Some(samples) = &mut recorder.rtt_samples {
+            samples.push(rtt);
+        }
+        recorder.rtt
+    }
+}
@@ -0,0 +1,469 @@
+//! File upload with integrity verification.
+//!
+//! The client sends a metadata header, the content, then its SHA-256 as a
+//! trailer:
+//!
+//! ```text
+//! name length: u16 | name: UTF-8 | size: u64 | mode: u32 | content | SHA-256
+//! ```
+//!
+//! Integers are big-endian and the name is a bare file name. The server
+//! writes the content into a hidden file of the receive directory and,
+//! once the client has sent its FIN, checks the size and the hash before
+//! renaming the file into place. It answers with a single [`Status`] byte
+//! before its own FIN.
+
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    fmt::{self, Display},
+    fs::{self, File, Permissions},
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use anyhow::{anyhow, bail, Result};
+use sha2::{Digest, Sha256};
+use tokio::{
+    fs::File as AsyncFile,
+    io::{AsyncReadExt, DuplexStream},
+    sync::mpsc,
+};
+use tracing::{info, warn};
+
+use crate::{
+    client::{run_client, Chunks, ClientConfig},
+    server::{Reply, Sink},
+    socket::CHUNK_SIZE,
+    stats::ConnectionStats,
+    transport::DatagramTransport,
+};
+
+const HASH_LENGTH: usize = 32;
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Answer of the server to an upload
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Stored,
+    /// The header could not be parsed or more data than announced came
+    Malformed,
+    /// The name is not a bare file name
+    InvalidName,
+    /// Less data than announced came
+    Truncated,
+    ChecksumMismatch,
+    /// The server failed to write the file
+    StorageFailed,
+}
+
+impl Status {
+    pub fn code(self) -> u8 {
+        match self {
+            Status::Stored => 0,
+            Status::Malformed => 1,
+            Status::InvalidName => 2,
+            Status::Truncated => 3,
+            Status::ChecksumMismatch => 4,
+            Status::StorageFailed => 5,
+        }
+    }
+
+    pub fn from_code(code: u8) -> Option<Self> {
+        [
+            Status::Stored,
+            Status::Malformed,
+            Status::InvalidName,
+            Status::Truncated,
+            Status::ChecksumMismatch,
+            Status::StorageFailed,
+        ]
+        .iter()
+        .copied()
+        .find(|status| status.code() == code)
+    }
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Stored => "stored",
+            Status::Malformed => "malformed upload",
+            Status::InvalidName => "invalid file name",
+            Status::Truncated => "truncated upload",
+            Status::ChecksumMismatch => "checksum mismatch",
+            Status::StorageFailed => "cannot store the file",
+        })
+    }
+}
+
+/// What the header says about the file
+#[derive(Debug, Clone, PartialEq)]
+struct Metadata {
+    name: String,
+    size: u64,
+    mode: u32,
+}
+
+impl Metadata {
+    fn encode(&self) -> Result<Vec<u8>> {
+        let length = u16::try_from(self.name.len())?;
+        let mut header = Vec::with_capacity(14 + self.name.len());
+        header.extend(length.to_be_bytes());
+        header.extend(self.name.as_bytes());
+        header.extend(self.size.to_be_bytes());
+        header.extend(self.mode.to_be_bytes());
+        Ok(header)
+    }
+
+    /// Decodes a header from the start of `bytes`, returning it with its
+    /// length, or `None` if more bytes are needed
+    fn decode(bytes: &[u8]) -> Result<Option<(Self, usize)>, Status> {
+        let length = match bytes {
+            [high, low, ..] => u16::from_be_bytes([*high, *low]) as usize,
+            _ => return Ok(None),
+        };
+        let end = 2 + length + 8 + 4;
+        if bytes.len() < end {
+            return Ok(None);
+        }
+        let name = std::str::from_utf8(&bytes[2..2 + length])
+            .map_err(|_| Status::InvalidName)?;
+        let mut size = [0; 8];
+        size.copy_from_slice(&bytes[2 + length..end - 4]);
+        let mut mode = [0; 4];
+        mode.copy_from_slice(&bytes[end - 4..end]);
+        let metadata = Self {
+            name: name.into(),
+            size: u64::from_be_bytes(size),
+            mode: u32::from_be_bytes(mode),
+        };
+        Ok(Some((metadata, end)))
+    }
+}
+
+/// Whether `name` names a file right in the receive directory
+fn bare(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name != "."
+        && name != ".."
+        && !name.contains(&['/', '\0'][..])
+}
+
+/// Name of the hidden file the upload of `name` by `peer` is written into,
+/// which stays short whatever the length of `name`
+fn partial_name(name: &str, peer: SocketAddr) -> String {
+    let digest = Sha256::new()
+        .chain_update(name)
+        // a name never contains a NUL, which keeps the pairs apart
+        .chain_update([0])
+        .chain_update(peer.to_string())
+        .finalize();
+    let hex: String = digest[..16]
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect();
+    format!(".{}.part", hex)
+}
+
+/// Uploads the file at `path` to the server at `peer` over `transport`,
+/// failing unless the server stored it
+pub async fn send_file<T: DatagramTransport>(
+    transport: T,
+    peer: SocketAddr,
+    path: impl AsRef<Path>,
+    config: ClientConfig,
+) -> Result<()> {
+    let path = path.as_ref();
+    let name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| anyhow!("Cannot send {} by name", path.display()))?;
+    let file = AsyncFile::open(path).await?;
+    let stat = file.metadata().await?;
+    if !stat.is_file() {
+        bail!("{} is not a file", path.display());
+    }
+    let metadata = Metadata {
+        name: name.into(),
+        size: stat.len(),
+        mode: stat.permissions().mode() & 0o777,
+    };
+    if !bare(&metadata.name) {
+        bail!("Cannot send {} by name", path.display());
+    }
+    let (output, answer) = tokio::io::duplex(CHUNK_SIZE);
+    let answer = tokio::spawn(read_answer(answer));
+    let config = ClientConfig {
+        output: Some(Box::new(output)),
+        ..config
+    };
+    let chunks = upload(file, metadata.clone())?;
+    run_client(transport, peer, chunks, config).await?;
+    match answer.await?? {
+        Some(Status::Stored) => {
+            info!(name = %metadata.name, size = metadata.size, "file stored");
+            Ok(())
+        }
+        Some(status) => bail!("The server refused the file: {}", status),
+        None => bail!("The server did not answer"),
+    }
+}
+
+async fn read_answer(mut answer: DuplexStream) -> Result<Option<Status>> {
+    let mut data = Vec::new();
+    answer.read_to_end(&mut data).await?;
+    match data.as_slice() {
+        [] => Ok(None),
+        [code] => Status::from_code(*code)
+            .map(Some)
+            .ok_or_else(|| anyhow!("Unknown answer {}", code)),
+        _ => bail!("Unexpected answer of {} bytes", data.len()),
+    }
+}
+
+/// Chunks of the header, the content of `file` and the trailer
+fn upload(mut file: AsyncFile, metadata: Metadata) -> Result<Chunks> {
+    let header = metadata.encode()?;
+    let (tx, rx) = mpsc::channel(1);
+    tokio::spawn(async move {
+        if tx.send(Ok(header)).await.is_err() {
+            return;
+        }
+        let mut hasher = Sha256::new();
+        let mut sent = 0;
+        loop {
+            let mut chunk = vec![0; CHUNK_SIZE];
+            let length = match file.read(&mut chunk).await {
+                Ok(length) => length,
+                Err(err) => {
+                    let _ = tx.send(Err(err)).await;
+                    return;
+                }
+            };
+            if length == 0 {
+                break;
+            }
+            chunk.truncate(length);
+            hasher.update(&chunk);
+            sent += length as u64;
+            if tx.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+        let trailer = if sent == metadata.size {
+            Ok(hasher.finalize().to_vec())
+        } else {
+            Err(io::Error::other("The file changed while it was sent"))
+        };
+        let _ = tx.send(trailer).await;
+    });
+    Ok(rx)
+}
+
+/// Sink storing the files sent with [`send_file`] in a directory
+pub struct Uploads {
+    directory: PathBuf,
+    uploads: Mutex<HashMap<SocketAddr, Upload>>,
+}
+
+struct Upload {
+    reply: Reply,
+    /// Bytes of the header or the trailer received so far
+    pending: Vec<u8>,
+    file: Option<Partial>,
+    failure: Option<Status>,
+}
+
+/// File being received, hidden until it is complete
+struct Partial {
+    metadata: Metadata,
+    path: PathBuf,
+    writer: BufWriter<File>,
+    written: u64,
+    hasher: Sha256,
+}
+
+impl Uploads {
+    /// Stores files in `directory`, created if missing
+    pub fn create(directory: impl Into<PathBuf>) -> io::Result<Self> {
+        let directory = directory.into();
+        fs::create_dir_all(&directory)?;
+        Ok(Self {
+            directory,
+            uploads: Default::default(),
+        })
+    }
+
+    /// Takes the data of an upload, returning why it failed if it did
+    fn take(
+        &self,
+        peer: SocketAddr,
+        upload: &mut Upload,
+        data: &[u8],
+    ) -> Result<(), Status> {
+        let mut data = data;
+        if upload.file.is_none() {
+            upload.pending.extend(data);
+            let (metadata, length) = match Metadata::decode(&upload.pending)? {
+                Some(decoded) => decoded,
+                None => return Ok(()),
+            };
+            if !bare(&metadata.name) {
+                return Err(Status::InvalidName);
+            }
+            let path = self.directory.join(partial_name(&metadata.name, peer));
+            let writer = File::create(&path).map_err(|err| {
+                let path = path.display();
+                warn!(%peer, %path, %err, "cannot create file");
+                Status::StorageFailed
+            })?;
+            upload.file = Some(Partial {
+                metadata,
+                path,
+                writer: BufWriter::new(writer),
+                written: 0,
+                hasher: Sha256::new(),
+            });
+            let rest = upload.pending.split_off(length);
+            upload.pending = Vec::new();
+            return self.take(peer, upload, &rest);
+        }
+        if let Some(file) = &mut upload.file {
+            let left = file.metadata.size - file.written;
+            let length = data.len().min(left as usize);
+            let (content, rest) = data.split_at(length);
+            file.writer.write_all(content).map_err(|err| {
+                warn!(%peer, %err, "cannot write file");
+                Status::StorageFailed
+            })?;
+            file.hasher.update(content);
+            file.written += content.len() as u64;
+            data = rest;
+        }
+        upload.pending.extend(data);
+        if upload.pending.len() > HASH_LENGTH {
+            return Err(Status::Malformed);
+        }
+        Ok(())
+    }
+
+    /// Checks a complete upload and moves it into place
+    fn store(&self, peer: SocketAddr, upload: Upload) -> Status {
+        let Partial {
+            metadata,
+            path: partial,
+            writer,
+            written,
+            hasher,
+        } = match upload.file {
+            Some(file) => file,
+            None if upload.pending.is_empty() => return Status::Malformed,
+            None => return Status::Truncated,
+        };
+        let status = if written != metadata.size
+            || upload.pending.len() != HASH_LENGTH
+        {
+            Status::Truncated
+        } else if hasher.finalize().as_slice() != upload.pending {
+            Status::ChecksumMismatch
+        } else {
+            let path = self.directory.join(&metadata.name);
+            let permissions = Permissions::from_mode(metadata.mode & 0o777);
+            let stored = writer
+                .into_inner()
+                .map_err(|err| err.into_error())
+                .and_then(|writer| {
+                    writer.set_permissions(permissions)?;
+                    writer.sync_all()
+                })
+                .and_then(|_| fs::rename(&partial, &path));
+            match stored {
+                Ok(()) => {
+                    info!(
+                        %peer,
+                        path = %path.display(),
+                        size = metadata.size,
+                        "file stored"
+                    );
+                    return Status::Stored;
+                }
+                Err(err) => {
+                    let path = path.display();
+                    warn!(%peer, %path, %err, "cannot store file");
+                    Status::StorageFailed
+                }
+            }
+        };
+        discard(&partial);
+        status
+    }
+}
+
+fn discard(path: &Path) {
+    if let Err(err) = fs::remove_file(path) {
+        if err.kind() != io::ErrorKind::NotFound {
+            warn!(path = %path.display(), %err, "cannot remove file");
+        }
+    }
+}
+
+impl Sink for Uploads {
+    fn open(&self, peer: SocketAddr, reply: Reply) {
+        let upload = Upload {
+            reply,
+            pending: Vec::new(),
+            file: None,
+            failure: None,
+        };
+        self.uploads.lock().unwrap().insert(peer, upload);
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        let mut uploads = self.uploads.lock().unwrap();
+        let upload = match uploads.get_mut(&peer) {
+            Some(upload) if upload.failure.is_none() => upload,
+            _ => return,
+        };
+        if let Err(status) = self.take(peer, upload, &data) {
+            warn!(%peer, %status, "upload failed");
+            upload.failure = Some(status);
+            if let Some(file) = upload.file.take() {
+                drop(file.writer);
+                discard(&file.path);
+            }
+        }
+    }
+
+    fn finish(&self, peer: SocketAddr) {
+        let upload = match self.uploads.lock().unwrap().remove(&peer) {
+            Some(upload) => upload,
+            None => return,
+        };
+        let reply = upload.reply.clone();
+        let status = match upload.failure {
+            Some(status) => status,
+            None => {
+                let status = self.store(peer, upload);
+                if status != Status::Stored {
+                    warn!(%peer, %status, "upload failed");
+                }
+                status
+            }
+        };
+        reply.send(vec![status.code()]);
+    }
+
+    fn close(&self, peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+        if let Some(upload) = self.uploads.lock().unwrap().remove(&peer) {
+            if let Some(file) = upload.file {
+                drop(file.writer);
+                discard(&file.path);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use std::{
+    fs,
+    future::Future,
+    io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+
+use rand::{thread_rng, Rng};
+use tokio::net::{UdpSocket, UnixDatagram};
+
+/// Unreliable datagram service the protocol runs on top of.
+pub trait DatagramTransport: Send + Sync + 'static {
+    fn send_to(
+        &self,
+        buffer: &[u8],
+        target: SocketAddr,
+    ) -> impl Future<Output = io::Result<usize>> + Send;
+
+    fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> impl Future<Output = io::Result<(usize, SocketAddr)>> + Send;
+
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+}
+
+impl DatagramTransport for UdpSocket {
+    async fn send_to(
+        &self,
+        buffer: &[u8],
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        UdpSocket::send_to(self, buffer, target).await
+    }
+
+    async fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        UdpSocket::recv_from(self, buffer).await
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
+
+/// Unix datagram socket living in a directory, where every address is
+/// a socket file named after it.
+pub struct UnixTransport {
+    socket: UnixDatagram,
+    directory: PathBuf,
+    address: SocketAddr,
+}
+
+impl UnixTransport {
+    /// Binds a socket for `address`, picking a random port if it is zero.
+    /// A stale socket file of an explicitly given address is replaced.
+    pub fn bind(
+        directory: impl Into<PathBuf>,
+        address: SocketAddr,
+    ) -> io::Result<Self> {
+        let directory = directory.into();
+        if address.port() != 0 {
+            let path = directory.join(address.to_string());
+            if path.exists() {
+                fs::remove_file(&path)?;
+            }
+            return Self::bind_exactly(directory, address);
+        }
+        loop {
+            let mut address = address;
+            address.set_port(thread_rng().gen_range(49152..=u16::MAX));
+            match Self::bind_exactly(directory.clone(), address) {
+                Err(err) if err.kind() == io::ErrorKind::AddrInUse => {}
+                result => break result,
+            }
+        }
+    }
+
+    fn bind_exactly(
+        directory: PathBuf,
+        address: SocketAddr,
+    ) -> io::Result<Self> {
+        let socket = UnixDatagram::bind(directory.join(address.to_string()))?;
+        Ok(Self {
+            socket,
+            directory,
+            address,
+        })
+    }
+}
+
+impl DatagramTransport for UnixTransport {
+    async fn send_to(
+        &self,
+        buffer: &[u8],
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        let path = self.directory.join(target.to_string());
+        self.socket.send_to(buffer, path).await
+    }
+
+    /// Skips datagrams of senders not bound the way [`UnixTransport`] does
+    async fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        loop {
+            let (length, sender) = self.socket.recv_from(buffer).await?;
+            let address = sender
+                .as_pathname()
+                .and_then(Path::file_name)
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.parse().ok());
+            if let Some(address) = address {
+                break Ok((length, address));
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.address)
+    }
+}
+
+impl Drop for UnixTransport {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(self.directory.join(self.address.to_string()));
+    }
+}
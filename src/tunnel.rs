@@ -0,0 +1,195 @@
+//! Port forwarding of TCP connections over udptcp.
+//!
+//! The client accepts TCP connections and carries each over a udptcp
+//! connection of its own, the server connects each udptcp connection to a
+//! TCP backend. Either side of a TCP connection closing its write half
+//! ends the matching direction of the udptcp connection.
+
+use std::{collections::HashMap, future::Future, net::SocketAddr, sync::Mutex};
+
+use anyhow::Result;
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+};
+use tracing::{debug, info, warn, Instrument};
+
+use crate::{
+    client::{connect_udp, read_chunks_async, run_client, ClientConfig},
+    server::{Reply, Sink},
+    socket::CHUNK_SIZE,
+    stats::ConnectionStats,
+};
+
+/// Forwards every connection accepted by `listener` to the server at
+/// `server`, each over a udptcp connection set up by `config`
+pub async fn forward(
+    listener: TcpListener,
+    server: SocketAddr,
+    config: impl Fn() -> ClientConfig,
+) -> Result<()> {
+    info!(address = %listener.local_addr()?, %server, "forwarding");
+    loop {
+        let (stream, address) = listener.accept().await?;
+        debug!(%address, "accepted");
+        let config = config();
+        tokio::spawn(async move {
+            if let Err(err) = carry(stream, server, config).await {
+                warn!(%address, %err, "forwarding failed");
+            }
+        });
+    }
+}
+
+async fn carry(
+    stream: TcpStream,
+    server: SocketAddr,
+    config: ClientConfig,
+) -> Result<()> {
+    let (socket, peer) = connect_udp(server).await?;
+    let (reader, writer) = stream.into_split();
+    let config = ClientConfig {
+        output: Some(Box::new(writer)),
+        ..config
+    };
+    run_client(socket, peer, read_chunks_async(reader), config).await
+}
+
+/// Sink connecting every connection to a TCP backend, sending back what
+/// the backend answers
+pub struct Backend {
+    address: String,
+    tunnels: Tunnels,
+}
+
+impl Backend {
+    /// Connects to `address`, resolved anew for every connection
+    pub fn new(address: impl Into<String>) -> Self {
+        Self {
+            address: address.into(),
+            tunnels: Tunnels::default(),
+        }
+    }
+}
+
+impl Sink for Backend {
+    fn open(&self, peer: SocketAddr, reply: Reply) {
+        let address = self.address.clone();
+        self.tunnels.open(peer, move |upstream| async move {
+            match TcpStream::connect(&address).await {
+                Ok(backend) => {
+                    let (reader, writer) = backend.into_split();
+                    shuttle(peer, upstream, writer, reader, reply).await
+                }
+                Err(err) => {
+                    warn!(%peer, %address, %err, "cannot connect to backend");
+                    reply.reset();
+                }
+            }
+        });
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        self.tunnels.receive(peer, data)
+    }
+
+    fn finish(&self, peer: SocketAddr) {
+        self.tunnels.finish(peer)
+    }
+
+    fn close(&self, peer: SocketAddr, _clean: bool, _stats: &ConnectionStats) {
+        self.tunnels.close(peer)
+    }
+}
+
+/// Tasks carrying the data of every connection to where it is destined
+#[derive(Default)]
+pub(crate) struct Tunnels(Mutex<HashMap<SocketAddr, Tunnel>>);
+
+struct Tunnel {
+    /// Data of the peer, `None` once it has sent everything
+    upstream: Option<UnboundedSender<Vec<u8>>>,
+    task: JoinHandle<()>,
+}
+
+impl Tunnels {
+    /// Runs `tunnel` for the connection with `peer`, handing it the data
+    /// the peer sends
+    pub(crate) fn open<F, T>(&self, peer: SocketAddr, tunnel: F)
+    where
+        F: FnOnce(UnboundedReceiver<Vec<u8>>) -> T,
+        T: Future<Output = ()> + Send + 'static,
+    {
+        let (upstream, receiver) = mpsc::unbounded_channel();
+        let task = tokio::spawn(tunnel(receiver).in_current_span());
+        let tunnel = Tunnel {
+            upstream: Some(upstream),
+            task,
+        };
+        self.0.lock().unwrap().insert(peer, tunnel);
+    }
+
+    pub(crate) fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        let tunnels = self.0.lock().unwrap();
+        let upstream = tunnels
+            .get(&peer)
+            .and_then(|tunnel| tunnel.upstream.as_ref());
+        if let Some(upstream) = upstream {
+            // the tunnel is gone if it failed, and the data with it
+            let _ = upstream.send(data);
+        }
+    }
+
+    pub(crate) fn finish(&self, peer: SocketAddr) {
+        if let Some(tunnel) = self.0.lock().unwrap().get_mut(&peer) {
+            tunnel.upstream = None;
+        }
+    }
+
+    /// Stops the tunnel, which is over already if the connection closed
+    /// cleanly
+    pub(crate) fn close(&self, peer: SocketAddr) {
+        if let Some(tunnel) = self.0.lock().unwrap().remove(&peer) {
+            tunnel.task.abort();
+        }
+    }
+}
+
+/// Carries the data of `peer` to `writer` and what `reader` yields back
+/// until both directions are closed, ending the replies as soon as
+/// `reader` does. The connection is reset if either direction fails.
+pub(crate) async fn shuttle(
+    peer: SocketAddr,
+    mut upstream: UnboundedReceiver<Vec<u8>>,
+    mut writer: impl AsyncWrite + Unpin,
+    mut reader: impl AsyncRead + Unpin,
+    reply: Reply,
+) {
+    let upstream = async {
+        while let Some(data) = upstream.recv().await {
+            writer.write_all(&data).await?;
+        }
+        writer.shutdown().await
+    };
+    let downstream = async {
+        let mut buffer = vec![0; CHUNK_SIZE];
+        loop {
+            reply.ready().await;
+            let length = reader.read(&mut buffer).await?;
+            if length == 0 {
+                reply.finish();
+                break Ok(());
+            }
+            if !reply.send(buffer[..length].to_vec()) {
+                break Ok(());
+            }
+        }
+    };
+    let (upstream, downstream) = tokio::join!(upstream, downstream);
+    if let Err(err) = upstream.and(downstream) {
+        warn!(%peer, %err, "tunnel failed");
+        reply.reset();
+    }
+}
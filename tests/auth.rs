@@ -0,0 +1,98 @@
+use std::{io::Cursor, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, sync::mpsc, time};
+
+use udptcp::{
+    auth::Psk,
+    client::{read_chunks_async, run_client, ClientConfig},
+    metrics::Metrics,
+    packet::{Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq},
+    server::{run_server, ServerConfig},
+};
+
+fn packet(data: &[u8]) -> Packet {
+    Packet::from(PseudoPacket {
+        source: SocketAddr::from(([10, 0, 0, 2], 5000)),
+        dest: SocketAddr::from(([10, 0, 0, 1], 7)),
+        seq: Seq(1),
+        extra: PacketExtra {
+            ack: Ack(2),
+            flags: Flags::default().flip_ack(),
+            data: data.into(),
+            ..Default::default()
+        },
+    })
+}
+
+#[test]
+fn signatures_cover_the_segment() {
+    let psk = Psk::new(b"secret".to_vec()).unwrap();
+    let other = Psk::new(b"other".to_vec()).unwrap();
+    let mut signed = packet(b"data");
+    psk.sign(&mut signed);
+    assert!(psk.verify(&signed));
+    assert!(!other.verify(&signed));
+    assert!(!psk.verify(&packet(b"data")), "unsigned packet verified");
+
+    let mut bytes = signed.into_bytes();
+    *bytes.last_mut().unwrap() ^= 1;
+    let tampered = Packet::from_bytes(bytes).unwrap();
+    assert!(!psk.verify(&tampered));
+    assert!(Psk::new(Vec::new()).is_err());
+}
+
+/// Runs a client with `psk` against a server with the key `secret`,
+/// returning whether the transfer completed and how many segments the
+/// server dropped for their MAC
+async fn transfer(psk: Option<Psk>) -> (bool, u64) {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let metrics = Arc::new(Metrics::default());
+    let (tx, mut received) = mpsc::unbounded_channel();
+    let config = ServerConfig {
+        psk: Some(Psk::new(b"secret".to_vec()).unwrap()),
+        sink: Some(Arc::new(move |_, data| tx.send(data).unwrap())),
+        metrics: Some(metrics.clone()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = ClientConfig {
+        psk,
+        ..Default::default()
+    };
+    let chunks = read_chunks_async(Cursor::new(b"signed".to_vec()));
+    let client = run_client(socket, address, chunks, config);
+    let completed = time::timeout(Duration::from_secs(1), client).await;
+    server.abort();
+    let completed = completed.is_ok_and(|result| result.is_ok());
+    if completed {
+        assert_eq!(received.recv().await.unwrap(), b"signed");
+    }
+    let rendered = metrics.render();
+    let dropped = rendered
+        .lines()
+        .find_map(|line| {
+            line.strip_prefix("udptcp_authentication_failures_total ")
+        })
+        .unwrap();
+    (completed, dropped.parse().unwrap())
+}
+
+#[tokio::test]
+async fn the_same_key_authenticates() {
+    let psk = Psk::new(b"secret".to_vec()).unwrap();
+    assert_eq!(transfer(Some(psk)).await, (true, 0));
+}
+
+#[tokio::test]
+async fn segments_without_the_key_are_dropped() {
+    let other = Psk::new(b"other".to_vec()).unwrap();
+    for psk in [Some(other), None] {
+        let (completed, dropped) = transfer(psk).await;
+        assert!(!completed, "connection without the key");
+        // the SYN and its retransmissions
+        assert!(dropped >= 2, "{} dropped", dropped);
+    }
+}
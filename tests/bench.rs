@@ -0,0 +1,140 @@
+use std::{io::Cursor, net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{net::UdpSocket, time};
+
+use udptcp::{
+    bench::{run_bench, BenchConfig, Limit, Meter, RttDistribution},
+    client::{read_chunks_async, run_client, ClientConfig},
+    packet::Seq,
+    server::{run_server, ServerConfig},
+    socket::CHUNK_SIZE,
+    stats::Stats,
+};
+
+#[tokio::test]
+async fn parallel_connections_send_up_to_the_limit() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Meter::default())),
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+
+    let bench = BenchConfig {
+        limit: Limit::Bytes(50_000),
+        interval: Duration::from_millis(10),
+        parallel: 2,
+    };
+    let mut intervals = Vec::new();
+    let reports = run_bench(address, bench, Default::default, |report| {
+        intervals.push(report.clone())
+    })
+    .await
+    .unwrap();
+
+    let connections: Vec<_> = reports.iter().map(|r| r.connection).collect();
+    assert_eq!(connections, [Some(1), Some(2), None]);
+    for report in &reports[..2] {
+        assert_eq!(report.bytes, 50_000);
+        assert!(report.segments >= 49);
+        assert!(report.rtt.is_some());
+        let interval_bytes: u64 = intervals
+            .iter()
+            .filter(|interval| interval.connection == report.connection)
+            .map(|interval| interval.bytes)
+            .sum();
+        assert_eq!(interval_bytes, report.bytes);
+    }
+    assert_eq!(reports[2].bytes, 100_000);
+    assert_eq!(reports[2].cwnd, reports[0].cwnd + reports[1].cwnd);
+    assert!(reports[2].goodput() > 0.0);
+}
+
+#[tokio::test]
+async fn time_limited_connections_stop() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Meter::default())),
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+
+    let bench = BenchConfig {
+        limit: Limit::Time(Duration::from_millis(300)),
+        interval: Duration::from_millis(100),
+        parallel: 1,
+    };
+    let mut intervals = 0;
+    let reports =
+        run_bench(address, bench, Default::default, |_| intervals += 1)
+            .await
+            .unwrap();
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].end >= Duration::from_millis(300));
+    assert!(reports[0].bytes > 0);
+    assert!(intervals >= 3);
+}
+
+#[tokio::test]
+async fn stats_keep_what_the_reports_need() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Meter::default())),
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+
+    let stats = Stats::default();
+    stats.keep_rtt_samples();
+    let config = ClientConfig {
+        stats: Some(stats.clone()),
+        ..Default::default()
+    };
+    let length = 5 * CHUNK_SIZE / 2;
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let chunks = read_chunks_async(Cursor::new(vec![1; length]));
+    let client = run_client(socket, address, chunks, config);
+    time::timeout(Duration::from_secs(5), client)
+        .await
+        .expect("client still running")
+        .unwrap();
+    assert_eq!(stats.snapshot().bytes_acked, length as u64);
+    assert!(!stats.take_rtt_samples().is_empty());
+    assert!(stats.take_rtt_samples().is_empty());
+}
+
+#[test]
+fn rtt_distribution_uses_nearest_ranks() {
+    let samples: Vec<_> = (1..=100).rev().map(Duration::from_millis).collect();
+    let rtt = RttDistribution::of(&samples).unwrap();
+    assert_eq!(rtt.samples, 100);
+    assert_eq!(rtt.min, Duration::from_millis(1));
+    assert_eq!(rtt.median, Duration::from_millis(50));
+    assert_eq!(rtt.p90, Duration::from_millis(90));
+    assert_eq!(rtt.p99, Duration::from_millis(99));
+    assert_eq!(rtt.max, Duration::from_millis(100));
+    assert_eq!(RttDistribution::of(&[]), None);
+}
+
+#[tokio::test]
+async fn panicked_connections_fail_the_measurement() {
+    let bench = BenchConfig {
+        limit: Limit::Bytes(50_000),
+        interval: Duration::from_millis(10),
+        parallel: 2,
+    };
+    let config = || ClientConfig {
+        isn: Some(Arc::new(|_, _| -> Seq { panic!("no sequence number") })),
+        ..Default::default()
+    };
+    let address = SocketAddr::from(([127, 0, 0, 1], 9));
+    let bench = run_bench(address, bench, config, |_| {});
+    let err = time::timeout(Duration::from_secs(5), bench)
+        .await
+        .expect("measurement still running")
+        .unwrap_err();
+    assert_eq!(err.to_string(), "Connection 1 panicked");
+}
@@ -0,0 +1,42 @@
+//! Helpers shared by the integration tests.
+
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use udptcp::{
+    server::{Reply, Sink},
+    sink::Discard,
+    stats::ConnectionStats,
+};
+
+/// Sink passing everything on to another one, by default dropping the
+/// data, and reporting whether every connection closed cleanly
+pub struct Closed<S = Discard>(S, UnboundedSender<bool>);
+
+impl<S> Closed<S> {
+    /// Wraps `sink`, returning the receiver of the reports
+    pub fn new(sink: S) -> (Self, UnboundedReceiver<bool>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(sink, tx), rx)
+    }
+}
+
+impl<S: Sink> Sink for Closed<S> {
+    fn open(&self, peer: SocketAddr, reply: Reply) {
+        self.0.open(peer, reply)
+    }
+
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        self.0.receive(peer, data)
+    }
+
+    fn finish(&self, peer: SocketAddr) {
+        self.0.finish(peer)
+    }
+
+    fn close(&self, peer: SocketAddr, clean: bool, stats: &ConnectionStats) {
+        self.0.close(peer, clean, stats);
+        self.1.send(clean).unwrap();
+    }
+}
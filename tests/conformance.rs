@@ -0,0 +1,38 @@
+mod script;
+
+use script::{run, Target};
+
+#[test]
+fn server_transfer() {
+    run("server_transfer.pkt", Target::Server);
+}
+
+#[test]
+fn server_duplicates() {
+    run("server_duplicates.pkt", Target::Server);
+}
+
+#[test]
+fn server_handshake_reset() {
+    run("server_handshake_reset.pkt", Target::Server);
+}
+
+#[test]
+fn client_transfer() {
+    run("client_transfer.pkt", Target::Client);
+}
+
+#[test]
+fn client_lost_handshake_ack() {
+    run("client_lost_handshake_ack.pkt", Target::Client);
+}
+
+#[test]
+fn client_refused() {
+    run("client_refused.pkt", Target::Client);
+}
+
+#[test]
+fn client_reset() {
+    run("client_reset.pkt", Target::Client);
+}
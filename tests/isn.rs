@@ -0,0 +1,56 @@
+use std::{net::SocketAddr, time::Duration};
+
+use tokio::time;
+
+use udptcp::{
+    isn::{IsnGenerator, Rfc6528},
+    packet::Seq,
+};
+
+fn address(last: u8, port: u16) -> SocketAddr {
+    SocketAddr::from(([10, 0, 0, last], port))
+}
+
+#[tokio::test(start_paused = true)]
+async fn connections_get_distinct_isns() {
+    let isn = Rfc6528::with_key((1, 2));
+    let server = address(1, 7);
+    let tuples = [
+        (address(2, 5000), server),
+        (address(2, 5001), server),
+        (address(3, 5000), server),
+        (address(2, 5000), address(1, 8)),
+        (server, address(2, 5000)),
+    ];
+    let isns: Vec<u32> = tuples
+        .iter()
+        .map(|(source, dest)| isn.generate(*source, *dest).0)
+        .collect();
+    for (i, a) in isns.iter().enumerate() {
+        for b in &isns[i + 1..] {
+            assert_ne!(a, b, "{:?}", isns);
+        }
+    }
+    // the same secret gives the same numbers
+    let again = Rfc6528::with_key((1, 2));
+    let (source, dest) = tuples[0];
+    assert_eq!(again.generate(source, dest), Seq(isns[0]));
+}
+
+#[tokio::test(start_paused = true)]
+async fn isns_advance_with_the_clock() {
+    let isn = Rfc6528::with_key((1, 2));
+    let (source, dest) = (address(2, 5000), address(1, 7));
+    let first = isn.generate(source, dest).0;
+    assert_eq!(isn.generate(source, dest).0, first, "clock is paused");
+
+    time::advance(Duration::from_millis(1)).await;
+    let second = isn.generate(source, dest).0;
+    // a tick every 4 microseconds
+    assert_eq!(second.wrapping_sub(first), 250);
+
+    time::advance(Duration::from_secs(3600)).await;
+    let third = isn.generate(source, dest).0;
+    let ticks = (3_600_000_000u64 + 1000) / 4;
+    assert_eq!(u64::from(third.wrapping_sub(first)), ticks % (1 << 32));
+}
@@ -0,0 +1,188 @@
+mod common;
+
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    sync::mpsc::{self, Sender},
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use udptcp::{
+    client::{run_client, ClientConfig},
+    keepalive::KeepAlive,
+    packet::Packet,
+    server::{run_server, ServerConfig},
+    sim::Network,
+    sink::Discard,
+    socket::MAX_PACKET_SIZE,
+    transport::DatagramTransport,
+};
+
+use common::Closed;
+
+const SERVER: ([u8; 4], u16) = ([10, 0, 0, 1], 7);
+const CLIENT: ([u8; 4], u16) = ([10, 0, 0, 2], 8);
+/// Where the client reaches the server, through a relay
+const RELAY: ([u8; 4], u16) = ([10, 0, 0, 3], 7);
+
+fn keepalive() -> KeepAlive {
+    KeepAlive {
+        idle: Duration::from_secs(10),
+        interval: Duration::from_secs(1),
+        probes: 3,
+    }
+}
+
+/// Server and client of a connection
+struct Connected {
+    server: JoinHandle<anyhow::Result<()>>,
+    /// Between the two, so that the server can vanish with it
+    relay: JoinHandle<()>,
+    input: Sender<std::io::Result<Vec<u8>>>,
+    client: JoinHandle<anyhow::Result<()>>,
+}
+
+/// Starts a server and a client across `network`, returning once the client
+/// sent a first chunk and it was acknowledged
+async fn connect(
+    network: &Network,
+    server: ServerConfig,
+    client: ClientConfig,
+) -> Connected {
+    let address = SocketAddr::from(SERVER);
+    let socket = network.bind(address).unwrap();
+    let server = tokio::spawn(run_server(socket, server));
+    let relay = network.bind(SocketAddr::from(RELAY)).unwrap();
+    let relay = tokio::spawn(async move {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (length, from) = relay.recv_from(&mut buffer).await.unwrap();
+            let to = if from == address {
+                SocketAddr::from(CLIENT)
+            } else {
+                address
+            };
+            relay.send_to(&buffer[..length], to).await.unwrap();
+        }
+    });
+    let socket = network.bind(SocketAddr::from(CLIENT)).unwrap();
+    let (input, chunks) = mpsc::channel(1);
+    let client = run_client(socket, SocketAddr::from(RELAY), chunks, client);
+    let client = tokio::spawn(client);
+    input.send(Ok(b"hello".to_vec())).await.unwrap();
+    time::sleep(Duration::from_millis(100)).await;
+    Connected {
+        server,
+        relay,
+        input,
+        client,
+    }
+}
+
+/// Takes the place at `address` of a peer that went away with `task`,
+/// returning the segments it goes on receiving
+async fn replace<T>(
+    network: &Network,
+    address: impl Into<SocketAddr>,
+    task: JoinHandle<T>,
+) -> Arc<Mutex<Vec<Packet>>> {
+    task.abort();
+    let _ = task.await;
+    let socket = network.bind(address.into()).unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let packets = received.clone();
+    tokio::spawn(async move {
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (length, _) = socket.recv_from(&mut buffer).await.unwrap();
+            let packet = Packet::from_bytes(buffer[..length].to_vec());
+            packets.lock().unwrap().push(packet.unwrap());
+        }
+    });
+    received
+}
+
+/// Number of keep-alive probes received, bare ACKs, leaving out the reset
+/// of a peer giving up
+fn probes(received: &Mutex<Vec<Packet>>) -> usize {
+    let received = received.lock().unwrap();
+    let probes = received.iter().filter(|packet| !packet.rst());
+    probes
+        .inspect(|packet| {
+            assert!(packet.flags().is_ack() && packet.data().is_empty())
+        })
+        .count()
+}
+
+#[tokio::test(start_paused = true)]
+async fn clients_time_out_once_probes_go_unanswered() {
+    let network = Network::new(Default::default(), 1).unwrap();
+    let client = ClientConfig {
+        keepalive: Some(keepalive()),
+        ..Default::default()
+    };
+    let server = ServerConfig {
+        sink: Some(Arc::new(|_, _| {})),
+        ..Default::default()
+    };
+    // the link has no delay, so traffic stops with the first chunk acked
+    let silent = Instant::now();
+    let connected = connect(&network, server, client).await;
+    let received = replace(&network, RELAY, connected.relay).await;
+
+    let err = connected.client.await.unwrap().unwrap_err();
+    assert!(err.to_string().contains("timed out"), "{}", err);
+    // idle, then an interval after each of the probes
+    assert_eq!(silent.elapsed(), Duration::from_secs(13));
+    assert_eq!(probes(&received), 3);
+    connected.server.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn servers_time_out_once_probes_go_unanswered() {
+    let network = Network::new(Default::default(), 1).unwrap();
+    let (sink, mut closed) = Closed::new(Discard);
+    let server = ServerConfig {
+        sink: Some(Arc::new(sink)),
+        keepalive: Some(keepalive()),
+        ..Default::default()
+    };
+    let silent = Instant::now();
+    let connected = connect(&network, server, Default::default()).await;
+    let received = replace(&network, CLIENT, connected.client).await;
+
+    assert!(!closed.recv().await.unwrap(), "closed cleanly");
+    assert_eq!(silent.elapsed(), Duration::from_secs(13));
+    assert_eq!(probes(&received), 3);
+    connected.server.abort();
+}
+
+#[tokio::test(start_paused = true)]
+async fn answered_probes_keep_idle_connections_open() {
+    let network = Network::new(Default::default(), 1).unwrap();
+    let (sink, mut closed) = Closed::new(Discard);
+    let server = ServerConfig {
+        sink: Some(Arc::new(sink)),
+        keepalive: Some(keepalive()),
+        ..Default::default()
+    };
+    let client = ClientConfig {
+        keepalive: Some(keepalive()),
+        ..Default::default()
+    };
+    let connected = connect(&network, server, client).await;
+
+    // far beyond the 13 seconds unanswered probes would last
+    time::sleep(Duration::from_secs(10 * 60)).await;
+    let input = connected.input;
+    input.send(Ok(b"still there".to_vec())).await.unwrap();
+    drop(input);
+    connected.client.await.unwrap().unwrap();
+    assert!(closed.recv().await.unwrap(), "closed uncleanly");
+    connected.server.abort();
+}
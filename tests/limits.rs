@@ -0,0 +1,190 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, Sender},
+    task::JoinHandle,
+    time,
+};
+
+use udptcp::{
+    client::run_client,
+    metrics::Metrics,
+    packet::{Flags, Packet, PacketExtra, PseudoPacket, Seq},
+    server::{Limits, Listener, ServerConfig, ShutdownHandle, Summary},
+    socket::MAX_PACKET_SIZE,
+};
+
+struct Server {
+    address: SocketAddr,
+    metrics: Arc<Metrics>,
+    handle: ShutdownHandle,
+    task: JoinHandle<Summary>,
+}
+
+impl Server {
+    async fn start(limits: Limits) -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let metrics = Arc::new(Metrics::default());
+        let config = ServerConfig {
+            limits,
+            sink: Some(Arc::new(|_, _| {})),
+            metrics: Some(metrics.clone()),
+            ..Default::default()
+        };
+        let listener = Listener::new(socket, config);
+        let handle = listener.shutdown_handle();
+        let task = tokio::spawn(async move { listener.run().await.unwrap() });
+        Self {
+            address,
+            metrics,
+            handle,
+            task,
+        }
+    }
+
+    /// Value of a gauge or counter of the server
+    fn metric(&self, name: &str) -> u64 {
+        let metrics = self.metrics.render();
+        let name = format!("udptcp_{} ", name);
+        let line = metrics.lines().find(|line| line.starts_with(&name));
+        line.unwrap()[name.len()..].parse().unwrap()
+    }
+
+    /// Shuts the server down once its connections are over
+    async fn summary(self) -> Summary {
+        self.handle.shutdown(Duration::from_secs(5));
+        time::timeout(Duration::from_secs(10), self.task)
+            .await
+            .expect("listener still running")
+            .unwrap()
+    }
+}
+
+/// Connects a client, established once this returns, sending whatever is
+/// written into the returned sender
+async fn connect(
+    server: SocketAddr,
+) -> (
+    Sender<std::io::Result<Vec<u8>>>,
+    JoinHandle<anyhow::Result<()>>,
+) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (input, chunks) = mpsc::channel(1);
+    let client =
+        tokio::spawn(run_client(socket, server, chunks, Default::default()));
+    input.send(Ok(b"hello".to_vec())).await.unwrap();
+    // the chunk is taken once the connection is established
+    input.reserve().await.unwrap();
+    (input, client)
+}
+
+/// Sends a bare segment with `flags` from `socket`
+async fn send(socket: &UdpSocket, server: SocketAddr, flags: Flags) {
+    let packet = Packet::from(PseudoPacket {
+        source: socket.local_addr().unwrap(),
+        dest: server,
+        seq: Seq(1000),
+        extra: PacketExtra {
+            flags,
+            ..Default::default()
+        },
+    });
+    socket.send_to(&packet.into_bytes(), server).await.unwrap();
+}
+
+async fn receive(socket: &UdpSocket) -> Packet {
+    let mut buffer = vec![0; MAX_PACKET_SIZE];
+    let received = socket.recv_from(&mut buffer);
+    let (length, _) = time::timeout(Duration::from_secs(5), received)
+        .await
+        .expect("no answer")
+        .unwrap();
+    buffer.truncate(length);
+    Packet::from_bytes(buffer).unwrap()
+}
+
+#[tokio::test]
+async fn connections_beyond_the_maximum_are_refused() {
+    let server = Server::start(Limits {
+        max_connections: 1,
+        ..Default::default()
+    })
+    .await;
+    let (input, client) = connect(server.address).await;
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_input, chunks) = mpsc::channel(1);
+    let refused =
+        run_client(socket, server.address, chunks, Default::default());
+    let refused = time::timeout(Duration::from_secs(5), refused).await;
+    assert!(refused.unwrap().is_err(), "connection accepted");
+    assert_eq!(server.metric("connections_established"), 1);
+    assert_eq!(server.metric("connections_refused_total"), 1);
+
+    drop(input);
+    client.await.unwrap().unwrap();
+    let summary = server.summary().await;
+    assert_eq!(summary.accepted, 1);
+    assert_eq!(summary.refused, 1);
+    assert_eq!(summary.closed, 1);
+}
+
+#[tokio::test]
+async fn handshakes_beyond_the_backlog_are_refused() {
+    let server = Server::start(Limits {
+        backlog: 1,
+        ..Default::default()
+    })
+    .await;
+    // never completes its handshake
+    let half_open = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    send(&half_open, server.address, Flags::default().flip_syn()).await;
+    let syn_ack = receive(&half_open).await;
+    assert!(syn_ack.flags().is_syn() && syn_ack.flags().is_ack());
+    assert_eq!(server.metric("connections_half_open"), 1);
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    send(&socket, server.address, Flags::default().flip_syn()).await;
+    let reset = receive(&socket).await;
+    assert!(reset.rst());
+    assert_eq!(reset.acknowledgment(), Seq(1001));
+    assert_eq!(server.metric("connections_half_open"), 1);
+    assert_eq!(server.metric("connections_refused_total"), 1);
+    assert_eq!(server.metric("connections_accepted_total"), 0);
+}
+
+#[tokio::test]
+async fn strays_are_reset_without_taking_a_slot() {
+    let server = Server::start(Limits {
+        max_connections: 1,
+        ..Default::default()
+    })
+    .await;
+    let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    send(&stray, server.address, Flags::default().flip_ack()).await;
+    let reset = receive(&stray).await;
+    assert!(reset.rst());
+    assert_eq!(server.metric("connections_half_open"), 0);
+
+    // resets are not answered
+    send(&stray, server.address, Flags::default().flip_rst()).await;
+    let answer = time::timeout(Duration::from_millis(100), receive(&stray));
+    assert!(answer.await.is_err(), "reset answered");
+
+    let (input, client) = connect(server.address).await;
+    drop(input);
+    client.await.unwrap().unwrap();
+    let summary = server.summary().await;
+    assert_eq!(
+        summary,
+        Summary {
+            accepted: 1,
+            refused: 0,
+            closed: 1,
+            failed: 0,
+            aborted: 0,
+        }
+    );
+}
@@ -0,0 +1,212 @@
+use std::{
+    collections::HashMap,
+    io::Cursor,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    task::JoinHandle,
+    time,
+};
+
+use udptcp::{
+    client::{read_chunks_async, run_client, ClientConfig},
+    server::{run_server, ServerConfig, Sink},
+    socket::CHUNK_SIZE,
+    stats::{ConnectionStats, Stats},
+};
+
+/// Records everything the server receives and reports closed connections
+struct Recorder {
+    data: Mutex<HashMap<SocketAddr, Vec<u8>>>,
+    closed: UnboundedSender<(SocketAddr, bool, ConnectionStats)>,
+}
+
+impl Sink for Recorder {
+    fn receive(&self, peer: SocketAddr, data: Vec<u8>) {
+        let mut received = self.data.lock().unwrap();
+        received.entry(peer).or_default().extend(data);
+    }
+
+    fn close(&self, peer: SocketAddr, clean: bool, stats: &ConnectionStats) {
+        self.closed.send((peer, clean, stats.clone())).unwrap();
+    }
+}
+
+struct Server {
+    address: SocketAddr,
+    recorder: Arc<Recorder>,
+    closed: UnboundedReceiver<(SocketAddr, bool, ConnectionStats)>,
+    task: JoinHandle<anyhow::Result<()>>,
+}
+
+impl Server {
+    async fn start() -> Self {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let address = socket.local_addr().unwrap();
+        let (tx, closed) = mpsc::unbounded_channel();
+        let recorder = Arc::new(Recorder {
+            data: Mutex::default(),
+            closed: tx,
+        });
+        let config = ServerConfig {
+            sink: Some(recorder.clone()),
+            ..Default::default()
+        };
+        let task = tokio::spawn(run_server(socket, config));
+        Self {
+            address,
+            recorder,
+            closed,
+            task,
+        }
+    }
+
+    /// Waits for the next connection to close
+    async fn closed(&mut self) -> Closed {
+        let closed = time::timeout(Duration::from_secs(10), self.closed.recv());
+        let (peer, clean, stats) = closed.await.unwrap().unwrap();
+        let mut data = self.recorder.data.lock().unwrap();
+        Closed {
+            peer,
+            clean,
+            data: data.remove(&peer).unwrap_or_default(),
+            stats,
+        }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Connection closed on the server, with what it received
+struct Closed {
+    peer: SocketAddr,
+    clean: bool,
+    data: Vec<u8>,
+    stats: ConnectionStats,
+}
+
+/// Sends `payload` to the server, returning the address of the client
+async fn send(server: SocketAddr, payload: Vec<u8>) -> SocketAddr {
+    send_with(server, payload, Default::default()).await
+}
+
+async fn send_with(
+    server: SocketAddr,
+    payload: Vec<u8>,
+    config: ClientConfig,
+) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let chunks = read_chunks_async(Cursor::new(payload));
+    let client = run_client(socket, server, chunks, config);
+    time::timeout(Duration::from_secs(60), client)
+        .await
+        .expect("client timed out")
+        .expect("client failed");
+    address
+}
+
+async fn transfer(payload: Vec<u8>) {
+    let mut server = Server::start().await;
+    let client = send(server.address, payload.clone()).await;
+    let Closed {
+        peer,
+        clean,
+        data: received,
+        ..
+    } = server.closed().await;
+    assert_eq!(peer, client);
+    assert!(clean, "server did not terminate the connection cleanly");
+    assert_eq!(received.len(), payload.len());
+    assert!(
+        received == payload,
+        "received data differs from the sent one"
+    );
+}
+
+#[tokio::test]
+async fn empty() {
+    transfer(Vec::new()).await;
+}
+
+#[tokio::test]
+async fn exactly_one_chunk() {
+    transfer((0..CHUNK_SIZE).map(|i| i as u8).collect()).await;
+}
+
+#[tokio::test]
+async fn multiple_megabytes() {
+    let length = 3 * 1024 * 1024 + 17;
+    transfer((0..length).map(|i| (i * 31 % 251) as u8).collect()).await;
+}
+
+#[tokio::test]
+async fn binary() {
+    // invalid as UTF-8 on its own, and a multibyte character split across
+    // the chunk boundary
+    let mut payload = vec![0xff, 0xfe, 0x00, 0x80, 0xc3];
+    payload.resize(CHUNK_SIZE - 1, 0xc0);
+    payload.extend("→".as_bytes());
+    payload.extend((0..=255).rev());
+    transfer(payload).await;
+}
+
+#[tokio::test]
+async fn concurrent_clients() {
+    let mut server = Server::start().await;
+    let payloads: Vec<Vec<u8>> =
+        (0..4u8).map(|i| vec![i; 5 * CHUNK_SIZE / 2]).collect();
+    let clients: Vec<_> = payloads
+        .iter()
+        .map(|payload| tokio::spawn(send(server.address, payload.clone())))
+        .collect();
+    let mut received = HashMap::new();
+    for _ in &clients {
+        let closed = server.closed().await;
+        assert!(closed.clean);
+        received.insert(closed.peer, closed.data);
+    }
+    for (client, payload) in clients.into_iter().zip(&payloads) {
+        assert!(received[&client.await.unwrap()] == *payload);
+    }
+}
+
+#[tokio::test]
+async fn statistics() {
+    let mut server = Server::start().await;
+    let stats = Stats::default();
+    let config = ClientConfig {
+        stats: Some(stats.clone()),
+        ..Default::default()
+    };
+    let length = 5 * CHUNK_SIZE / 2;
+    send_with(server.address, vec![1; length], config).await;
+    let client = stats.snapshot();
+    let server = server.closed().await.stats;
+
+    for stats in [&client, &server] {
+        assert_eq!(stats.state, "CLOSED");
+        assert!(stats.duration > Duration::ZERO);
+        assert!(stats.srtt.is_some());
+    }
+    // every data segment is acknowledged at least once
+    assert!(client.bytes_sent >= length as u64);
+    assert!(client.segments_sent >= 3 + 2);
+    assert!(client.segments_received >= 3 + 2);
+    assert_eq!(client.bytes_received, 0);
+    assert_eq!(client.rto, Some(Duration::from_millis(250)));
+    assert_eq!(server.bytes_received, client.bytes_sent);
+    assert_eq!(server.segments_received, client.segments_sent);
+    // the server may retransmit its FIN-ACK after the client is gone
+    assert!(server.segments_sent >= client.segments_received);
+    assert_eq!(server.rto, Some(Duration::from_millis(250)));
+}
@@ -0,0 +1,100 @@
+mod common;
+
+use std::{io::Cursor, net::SocketAddr, sync::Arc};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+};
+
+use udptcp::{
+    client::{read_chunks_async, run_client},
+    metrics::{serve, Metrics},
+    packet::{Flags, Packet, PacketExtra, PseudoPacket, Seq},
+    server::{run_server, ServerConfig},
+    sink::Discard,
+};
+
+use common::Closed;
+
+async fn scrape(address: SocketAddr) -> String {
+    let mut stream = TcpStream::connect(address).await.unwrap();
+    stream
+        .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200 OK\r\n"), "{}", response);
+    response
+}
+
+fn value(metrics: &str, name: &str) -> f64 {
+    metrics
+        .lines()
+        .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+        .unwrap_or_else(|| panic!("no {} in {}", name, metrics))
+        .parse()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn endpoint_reports_a_connection() {
+    let metrics = Arc::new(Metrics::default());
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let endpoint = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, metrics.clone()));
+
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (sink, mut closed) = Closed::new(Discard);
+    let config = ServerConfig {
+        sink: Some(Arc::new(sink)),
+        metrics: Some(metrics),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let before = scrape(endpoint).await;
+    assert_eq!(value(&before, "udptcp_connections_accepted_total"), 0.0);
+
+    let junk = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    junk.send_to(b"not a segment", address).await.unwrap();
+    let mut broken = Packet::from(PseudoPacket {
+        source: junk.local_addr().unwrap(),
+        dest: address,
+        seq: Seq(1),
+        extra: PacketExtra {
+            flags: Flags::default().flip_syn(),
+            ..Default::default()
+        },
+    });
+    broken.set_checksum(!broken.checksum());
+    junk.send_to(&broken.into_bytes(), address).await.unwrap();
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let chunks = read_chunks_async(Cursor::new(vec![0; 3000]));
+    run_client(socket, address, chunks, Default::default())
+        .await
+        .unwrap();
+    closed.recv().await.unwrap();
+    server.abort();
+
+    let after = scrape(endpoint).await;
+    assert_eq!(value(&after, "udptcp_connections_accepted_total"), 1.0);
+    assert_eq!(value(&after, "udptcp_malformed_datagrams_total"), 1.0);
+    assert_eq!(value(&after, "udptcp_checksum_failures_total"), 1.0);
+    assert_eq!(value(&after, "udptcp_connections_half_open"), 0.0);
+    assert!(value(&after, "udptcp_received_bytes_total") > 3000.0);
+    assert!(value(&after, "udptcp_sent_bytes_total") > 0.0);
+    assert_eq!(
+        value(&after, "udptcp_handshake_duration_seconds_count"),
+        1.0
+    );
+    assert_eq!(
+        value(
+            &after,
+            "udptcp_handshake_duration_seconds_bucket{le=\"+Inf\"}"
+        ),
+        1.0
+    );
+}
@@ -0,0 +1,240 @@
+use std::{
+    env, fs,
+    io::Cursor,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::UdpSocket,
+    time,
+};
+
+use udptcp::{
+    client::{read_chunks_async, run_client, ClientConfig},
+    noise::{NoiseKeys, TAG_LENGTH},
+    packet::{Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq},
+    server::{run_server, ServerConfig},
+    sink::Echo,
+    socket::{CHUNK_SIZE, MAX_PACKET_SIZE},
+};
+
+/// Directory of its own for every test
+fn directory(test: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!(
+        "udptcp-noise-{}-{}",
+        test,
+        process::id()
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    fs::create_dir(&directory).unwrap();
+    directory
+}
+
+/// Keys of `name`, pinning the public key of `peer` if given
+fn keys(directory: &Path, name: &str, peer: Option<&str>) -> NoiseKeys {
+    let peer = peer.map(|peer| directory.join(format!("{}.pub", peer)));
+    NoiseKeys::load(directory.join(name), peer).unwrap()
+}
+
+fn payload() -> Vec<u8> {
+    (0..3 * CHUNK_SIZE).map(|i| (i * 7 % 253) as u8).collect()
+}
+
+/// Relays between a client and the server at `server` through a socket of
+/// its own, forwarding whatever `edit` makes of every datagram of the
+/// client. Returns the address of the relay and every datagram it saw.
+async fn relay(
+    server: SocketAddr,
+    mut edit: impl FnMut(Vec<u8>) -> Vec<Vec<u8>> + Send + 'static,
+) -> (SocketAddr, Arc<Mutex<Vec<Vec<u8>>>>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let wire = Arc::new(Mutex::new(Vec::new()));
+    let seen = wire.clone();
+    tokio::spawn(async move {
+        let mut client = None;
+        let mut buffer = vec![0; MAX_PACKET_SIZE];
+        loop {
+            let (length, from) = socket.recv_from(&mut buffer).await.unwrap();
+            let datagram = buffer[..length].to_vec();
+            seen.lock().unwrap().push(datagram.clone());
+            if from == server {
+                if let Some(client) = client {
+                    socket.send_to(&datagram, client).await.unwrap();
+                }
+                continue;
+            }
+            client = Some(from);
+            for datagram in edit(datagram) {
+                socket.send_to(&datagram, server).await.unwrap();
+            }
+        }
+    });
+    (address, wire)
+}
+
+/// `datagram` with its payload replaced by `data`
+fn rewrite(datagram: &[u8], data: &[u8]) -> Vec<u8> {
+    let packet = Packet::from_bytes(datagram.to_vec()).unwrap();
+    let header = usize::from(packet.data_offset()) * 4;
+    let mut packet =
+        Packet::from_bytes([&datagram[..header], data].concat()).unwrap();
+    packet.update_checksum();
+    packet.into_bytes()
+}
+
+/// FIN at `seq` carrying `data` instead of a sealed payload
+fn forged_fin(seq: Seq, data: &[u8]) -> Vec<u8> {
+    Packet::from(PseudoPacket {
+        source: SocketAddr::from(([127, 0, 0, 1], 1)),
+        dest: SocketAddr::from(([127, 0, 0, 1], 2)),
+        seq,
+        extra: PacketExtra {
+            flags: Flags::default().flip_fin(),
+            data: data.into(),
+            ..Default::default()
+        },
+    })
+    .into_bytes()
+}
+
+#[tokio::test]
+async fn encrypted_connections_echo_with_pinned_keys() {
+    let directory = directory("echo");
+    // generates both key pairs before either is pinned
+    keys(&directory, "server", None);
+    keys(&directory, "client", None);
+    let server_keys = keys(&directory, "server", Some("client"));
+    let client_keys = keys(&directory, "client", Some("server"));
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Echo::default())),
+        noise: Some(server_keys),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+    let (relay, wire) = relay(address, |datagram| vec![datagram]).await;
+
+    let (writer, mut reader) = io::duplex(CHUNK_SIZE);
+    let echoed = tokio::spawn(async move {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        data
+    });
+    let config = ClientConfig {
+        noise: Some(client_keys),
+        output: Some(Box::new(writer)),
+        ..Default::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let chunks = read_chunks_async(Cursor::new(payload()));
+    run_client(socket, relay, chunks, config).await.unwrap();
+    server.abort();
+
+    assert!(echoed.await.unwrap() == payload(), "echo differs");
+    let plaintext = &payload()[..64];
+    let wire = wire.lock().unwrap();
+    assert!(wire.len() > 6);
+    for datagram in wire.iter() {
+        assert!(!datagram.windows(64).any(|window| window == plaintext));
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn unexpected_peer_keys_are_refused() {
+    let directory = directory("mismatch");
+    let server_keys = keys(&directory, "server", None);
+    keys(&directory, "other", None);
+    let client_keys = keys(&directory, "client", Some("other"));
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(|_, _| {})),
+        noise: Some(server_keys),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let config = ClientConfig {
+        noise: Some(client_keys),
+        ..Default::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let chunks = read_chunks_async(Cursor::new(payload()));
+    let client = run_client(socket, address, chunks, config);
+    let result = time::timeout(Duration::from_secs(5), client).await;
+    let err = result.expect("client still running").unwrap_err();
+    assert!(err.to_string().contains("unexpected static key"), "{}", err);
+    server.abort();
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn forged_payloads_and_fins_are_dropped() {
+    let directory = directory("forged");
+    let server_keys = keys(&directory, "server", None);
+    let client_keys = keys(&directory, "client", None);
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let config = ServerConfig {
+        sink: Some(Arc::new(move |_, data: Vec<u8>| {
+            sink.lock().unwrap().extend(data)
+        })),
+        noise: Some(server_keys),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+    // payloads of the data segments, by sequence number
+    let mut segments: Vec<(Ack, Vec<u8>)> = Vec::new();
+    let (relay, _) = relay(address, move |datagram| {
+        let packet = Packet::from_bytes(datagram.clone()).unwrap();
+        let (seq, data) = (packet.seq(), packet.data().to_vec());
+        if data.len() != CHUNK_SIZE + TAG_LENGTH
+            || segments.iter().any(|(seen, _)| *seen == seq)
+        {
+            return vec![datagram];
+        }
+        let forged = match segments.len() {
+            0 => {
+                let mut tampered = data.clone();
+                tampered[CHUNK_SIZE / 2] ^= 1;
+                vec![rewrite(&datagram, &tampered)]
+            }
+            // the first payload again, in place of the second one
+            1 => vec![rewrite(&datagram, &segments[0].1)],
+            // would end the stream before its last chunk
+            _ => {
+                let seq = Seq(seq.0);
+                vec![forged_fin(seq, &[]), forged_fin(seq, &[0; TAG_LENGTH])]
+            }
+        };
+        segments.push((seq, data));
+        [forged, vec![datagram]].concat()
+    })
+    .await;
+
+    let config = ClientConfig {
+        noise: Some(client_keys),
+        ..Default::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let chunks = read_chunks_async(Cursor::new(payload()));
+    let client = run_client(socket, relay, chunks, config);
+    time::timeout(Duration::from_secs(5), client)
+        .await
+        .expect("client still running")
+        .unwrap();
+    server.abort();
+
+    assert!(*received.lock().unwrap() == payload(), "forgery delivered");
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,147 @@
+use std::net::SocketAddr;
+
+use proptest::{collection::vec, prelude::*};
+
+use udptcp::{
+    packet::{
+        Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq, TcpOption,
+        WindowSize,
+    },
+    socket::CHUNK_SIZE,
+};
+
+/// Room for options in a header with the maximum data offset
+const OPTIONS_LENGTH: usize = 40;
+
+fn option() -> impl Strategy<Value = TcpOption> {
+    prop_oneof![
+        (any::<u8>(), any::<u8>(), vec(any::<u8>(), 0..=16)).prop_map(
+            |(key_id, next_key_id, mac)| TcpOption::Authentication {
+                key_id,
+                next_key_id,
+                mac,
+            }
+        ),
+        // End of Option List, No-Operation and Authentication aside
+        (2u8.., vec(any::<u8>(), 0..=8))
+            .prop_filter("known kind", |(kind, _)| *kind != 29)
+            .prop_map(|(kind, value)| TcpOption::Unknown { kind, value }),
+    ]
+}
+
+fn length(option: &TcpOption) -> usize {
+    match option {
+        TcpOption::Authentication { mac, .. } => 4 + mac.len(),
+        TcpOption::Unknown { value, .. } => 2 + value.len(),
+    }
+}
+
+fn options() -> impl Strategy<Value = Vec<TcpOption>> {
+    vec(option(), 0..=4).prop_filter("options fit into the header", |options| {
+        options.iter().map(length).sum::<usize>() <= OPTIONS_LENGTH
+    })
+}
+
+#[derive(Debug)]
+struct Fields {
+    source: SocketAddr,
+    dest: SocketAddr,
+    seq: u32,
+    ack: u32,
+    flags: u16,
+    window_size: u16,
+    urgent: u16,
+    options: Vec<TcpOption>,
+    data: Vec<u8>,
+}
+
+fn fields() -> impl Strategy<Value = Fields> {
+    (
+        (any::<SocketAddr>(), any::<SocketAddr>()),
+        (any::<u32>(), any::<u32>(), 0u16..1 << 12),
+        (any::<u16>(), any::<u16>()),
+        options(),
+        vec(any::<u8>(), 0..=CHUNK_SIZE),
+    )
+        .prop_map(
+            |(
+                (source, dest),
+                (seq, ack, flags),
+                (window_size, urgent),
+                options,
+                data,
+            )| Fields {
+                source,
+                dest,
+                seq,
+                ack,
+                flags,
+                window_size,
+                urgent,
+                options,
+                data,
+            },
+        )
+}
+
+impl Fields {
+    fn packet(&self) -> Packet {
+        Packet::from(PseudoPacket {
+            source: self.source,
+            dest: self.dest,
+            seq: Seq(self.seq),
+            extra: PacketExtra {
+                ack: Ack(self.ack),
+                flags: Flags::from_bits(self.flags),
+                window_size: WindowSize(self.window_size),
+                urgent: self.urgent,
+                options: self.options.clone(),
+                data: self.data.clone(),
+            },
+        })
+    }
+}
+
+proptest! {
+    #[test]
+    fn round_trip(fields in fields()) {
+        let packet = fields.packet();
+        let parsed = Packet::from_bytes(packet.clone().into_bytes()).unwrap();
+        prop_assert_eq!(&parsed, &packet);
+
+        prop_assert_eq!(parsed.source_port(), fields.source.port());
+        prop_assert_eq!(parsed.dest_port(), fields.dest.port());
+        prop_assert_eq!(parsed.seq(), Ack(fields.seq));
+        prop_assert_eq!(parsed.acknowledgment(), Ack(fields.ack));
+        prop_assert_eq!(parsed.flags().bits(), fields.flags);
+        prop_assert_eq!(parsed.window_size(), fields.window_size);
+        prop_assert_eq!(parsed.urgent(), fields.urgent);
+        prop_assert_eq!(parsed.options(), &fields.options[..]);
+        prop_assert_eq!(parsed.data(), &fields.data[..]);
+        let header = 20 + fields.options.iter().map(length).sum::<usize>();
+        prop_assert_eq!(usize::from(parsed.data_offset()), header.div_ceil(4));
+    }
+
+    #[test]
+    fn checksum(fields in fields()) {
+        let packet = fields.packet();
+        prop_assert!(packet.verify_checksum());
+        let parsed = Packet::from_bytes(packet.into_bytes()).unwrap();
+        prop_assert!(parsed.verify_checksum());
+    }
+
+    #[test]
+    fn checksum_detects_flipped_bits(
+        fields in fields(),
+        index in any::<prop::sample::Index>(),
+        bit in 0..8u8,
+    ) {
+        let mut bytes = fields.packet().into_bytes();
+        let header = bytes.len() - fields.data.len();
+        // the fixed part of the header before the data offset, and the data
+        let positions: Vec<usize> = (0..12).chain(header..bytes.len()).collect();
+        bytes[*index.get(&positions)] ^= 1 << bit;
+        let packet = Packet::from_bytes(bytes).unwrap();
+        prop_assert!(!packet.verify_checksum());
+    }
+}
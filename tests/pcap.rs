@@ -0,0 +1,142 @@
+use std::{
+    convert::TryInto,
+    env, fs,
+    io::{self, Cursor, Write},
+    net::{Ipv4Addr, SocketAddr},
+    process,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::net::UdpSocket;
+
+use udptcp::{
+    client::{read_chunks_async, run_client, ClientConfig},
+    packet::Packet,
+    pcap::Pcap,
+    server::{run_server, ServerConfig},
+};
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+/// One's complement sum of an IPv4 packet carrying TCP, over the segment
+/// and its pseudo-header
+fn tcp_sum(ip: &[u8]) -> u16 {
+    let segment = &ip[20..];
+    let length = (segment.len() as u16).to_be_bytes();
+    let pseudo_header = [&ip[12..20], &[0, 6], &length[..]].concat();
+    let mut sum: u32 = [&pseudo_header[..], segment]
+        .concat()
+        .chunks(2)
+        .map(|word| {
+            let lo = word.get(1).copied().unwrap_or(0);
+            (u32::from(word[0]) << 8) | u32::from(lo)
+        })
+        .sum();
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[tokio::test]
+async fn capture_decodes_as_tcp() {
+    let path = env::temp_dir().join(format!("udptcp-{}.pcap", process::id()));
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(|_, _| {})),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let socket = client.local_addr().unwrap();
+    let config = ClientConfig {
+        pcap: Some(Pcap::create(&path).unwrap()),
+        ..Default::default()
+    };
+    let chunks = read_chunks_async(Cursor::new(b"captured".to_vec()));
+    run_client(client, address, chunks, config).await.unwrap();
+    server.abort();
+
+    let capture = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    assert_eq!(u32_at(&capture, 0), 0xa1b2_c3d4);
+    assert_eq!(u32_at(&capture, 20), 101);
+    let mut offset = 24;
+    let mut records = 0;
+    while offset < capture.len() {
+        let length = u32_at(&capture, offset + 8) as usize;
+        let ip = &capture[offset + 16..offset + 16 + length];
+        offset += 16 + length;
+        records += 1;
+
+        assert_eq!(ip[0], 0x45, "not an IPv4 header without options");
+        assert_eq!(usize::from(u16::from_be_bytes([ip[2], ip[3]])), length);
+        assert_eq!(ip[9], 6, "not TCP");
+        let source: [u8; 4] = ip[12..16].try_into().unwrap();
+        let packet = Packet::from_bytes(ip[20..].into()).unwrap();
+        let source =
+            SocketAddr::from((Ipv4Addr::from(source), packet.source_port()));
+        assert!(source == socket || source == address, "{}", source);
+        assert_eq!(tcp_sum(ip), 0xffff, "wrong TCP checksum");
+    }
+    // handshake, data and teardown in both directions
+    assert!(records >= 7, "only {} records", records);
+}
+
+/// Writer running out of space after `left` bytes, counting the writes
+/// attempted from then on
+struct Full {
+    left: usize,
+    failed: Arc<AtomicUsize>,
+}
+
+impl Write for Full {
+    fn write(&mut self, buffer: &[u8]) -> io::Result<usize> {
+        if self.left == 0 {
+            self.failed.fetch_add(1, Ordering::Relaxed);
+            return Err(io::Error::other("no space left"));
+        }
+        let length = buffer.len().min(self.left);
+        self.left -= length;
+        Ok(length)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn failed_captures_stop_without_the_server() {
+    let failed = Arc::new(AtomicUsize::new(0));
+    let full = Full {
+        left: 200,
+        failed: failed.clone(),
+    };
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(|_, _| {})),
+        pcap: Some(Pcap::new(full).unwrap()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    for _ in 0..2 {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let chunks = read_chunks_async(Cursor::new(vec![0; 3000]));
+        run_client(client, address, chunks, Default::default())
+            .await
+            .unwrap();
+    }
+    assert!(!server.is_finished(), "server stopped: {:?}", server.await);
+    server.abort();
+    assert_eq!(failed.load(Ordering::Relaxed), 1);
+}
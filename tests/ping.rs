@@ -0,0 +1,124 @@
+use std::{future, net::SocketAddr, time::Duration};
+
+use tokio::net::UdpSocket;
+
+use udptcp::{
+    client::{run_ping, PingConfig, PingSummary, Probe},
+    server::{run_server, Limits, ServerConfig},
+    sim::{LinkConfig, Network},
+};
+
+fn ping(count: u32) -> PingConfig {
+    PingConfig {
+        count: Some(count),
+        interval: Duration::from_millis(10),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn probes_are_answered() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    tokio::spawn(run_server(server, ServerConfig::default()));
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let mut probes = Vec::new();
+    let summary = run_ping(
+        client,
+        address,
+        ping(3),
+        Default::default(),
+        future::pending(),
+        |number, probe| probes.push((number, probe)),
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.sent, 3);
+    assert_eq!(summary.rtts.len(), 3);
+    assert_eq!(summary.loss(), 0.0);
+    let numbers: Vec<_> = probes.iter().map(|(number, _)| *number).collect();
+    assert_eq!(numbers, [1, 2, 3]);
+    assert!(probes
+        .iter()
+        .all(|(_, probe)| matches!(probe, Probe::Answered(_))));
+}
+
+#[tokio::test]
+async fn full_servers_refuse_probes() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        limits: Limits {
+            max_connections: 0,
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let summary = run_ping(
+        client,
+        address,
+        ping(2),
+        Default::default(),
+        future::pending(),
+        |_, _| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.sent, 2);
+    assert_eq!(summary.refused, 2);
+    assert_eq!(summary.loss(), 1.0);
+}
+
+#[tokio::test(start_paused = true)]
+async fn lost_probes_are_counted() {
+    let link = LinkConfig {
+        loss: 0.3,
+        delay: Duration::from_millis(20),
+        ..Default::default()
+    };
+    let network = Network::new(link, 3).unwrap();
+    let address = SocketAddr::from(([10, 0, 0, 1], 7));
+    let server = network.bind(address).unwrap();
+    let client = network.bind(SocketAddr::from(([10, 0, 0, 2], 0))).unwrap();
+    tokio::spawn(run_server(server, ServerConfig::default()));
+    let config = PingConfig {
+        timeout: Duration::from_millis(200),
+        ..ping(20)
+    };
+    let summary = run_ping(
+        client,
+        address,
+        config,
+        Default::default(),
+        future::pending(),
+        |_, _| {},
+    )
+    .await
+    .unwrap();
+    assert_eq!(summary.sent, 20);
+    assert_eq!(summary.refused, 0);
+    assert!(0.0 < summary.loss() && summary.loss() < 1.0, "{}", summary);
+    assert!(summary.min().unwrap() >= Duration::from_millis(40));
+}
+
+#[test]
+fn summaries_have_rtt_statistics() {
+    let summary = PingSummary {
+        sent: 5,
+        refused: 1,
+        rtts: [4, 1, 3, 2]
+            .iter()
+            .copied()
+            .map(Duration::from_millis)
+            .collect(),
+    };
+    assert!((summary.loss() - 0.2).abs() < 1e-9);
+    assert_eq!(summary.min(), Some(Duration::from_millis(1)));
+    assert_eq!(summary.max(), Some(Duration::from_millis(4)));
+    assert_eq!(summary.avg(), Some(Duration::from_micros(2500)));
+    let stddev = summary.stddev().unwrap().as_secs_f64() * 1000.0;
+    assert!((stddev - 1.25f64.sqrt()).abs() < 1e-6);
+    assert_eq!(PingSummary::default().avg(), None);
+}
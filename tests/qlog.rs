@@ -0,0 +1,94 @@
+mod common;
+
+use std::{env, fs, io::Cursor, path::Path, process, sync::Arc};
+
+use serde_json::Value;
+use tokio::net::UdpSocket;
+
+use udptcp::{
+    client::{read_chunks_async, run_client, ClientConfig},
+    server::{run_server, ServerConfig},
+    sink::Discard,
+};
+
+use common::Closed;
+
+/// Reads the only log of `vantage` in `directory`, checking its events
+fn read_log(directory: &Path, vantage: &str) -> Vec<Value> {
+    let logs: Vec<_> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_str().unwrap().contains(vantage))
+        .collect();
+    assert_eq!(logs.len(), 1, "{:?}", logs);
+    let log = fs::read_to_string(&logs[0]).unwrap();
+    let mut lines = log.lines().map(|line| {
+        serde_json::from_str::<Value>(line).expect("line is not JSON")
+    });
+    let header = lines.next().unwrap();
+    assert_eq!(header["trace"]["vantage_point"]["type"], vantage);
+    let events: Vec<Value> = lines.collect();
+    let times: Vec<f64> = events
+        .iter()
+        .map(|event| event["time"].as_f64().unwrap())
+        .collect();
+    assert!(times.windows(2).all(|pair| pair[0] <= pair[1]));
+    events
+}
+
+fn states(events: &[Value]) -> Vec<&str> {
+    events
+        .iter()
+        .filter(|event| {
+            event["name"] == "connectivity:connection_state_updated"
+        })
+        .map(|event| event["data"]["new"].as_str().unwrap())
+        .collect()
+}
+
+fn count(events: &[Value], name: &str) -> usize {
+    events.iter().filter(|event| event["name"] == name).count()
+}
+
+#[tokio::test]
+async fn both_sides_log_the_connection() {
+    let directory =
+        env::temp_dir().join(format!("udptcp-qlog-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (sink, mut closed) = Closed::new(Discard);
+    let config = ServerConfig {
+        sink: Some(Arc::new(sink)),
+        qlog: Some(directory.clone()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let config = ClientConfig {
+        qlog: Some(directory.clone()),
+        ..Default::default()
+    };
+    let chunks = read_chunks_async(Cursor::new(vec![7; 3000]));
+    run_client(socket, address, chunks, config).await.unwrap();
+    closed.recv().await.unwrap();
+    server.abort();
+
+    let client = read_log(&directory, "client");
+    let server = read_log(&directory, "server");
+    fs::remove_dir_all(&directory).unwrap();
+    assert_eq!(
+        states(&client),
+        ["SYN-SENT", "ESTABLISHED", "FIN-WAIT", "CLOSED"]
+    );
+    assert_eq!(
+        states(&server),
+        ["SYN-RECEIVED", "ESTABLISHED", "LAST-ACK", "CLOSED"]
+    );
+    for events in [&client, &server] {
+        assert!(count(events, "transport:packet_sent") >= 3);
+        assert!(count(events, "transport:packet_received") >= 3);
+        assert!(count(events, "recovery:metrics_updated") >= 1);
+    }
+}
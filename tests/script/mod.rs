@@ -0,0 +1,415 @@
+//! Interpreter of packetdrill-like scripts pinning down the behaviour of
+//! a client or server connection segment by segment.
+//!
+//! Every line starts with a time in seconds, absolute since the start of
+//! the script or relative to the previous line if prefixed with `+`,
+//! followed by an event:
+//!
+//! ```text
+//! 0     < S 100:100(0)            inject a segment from the remote peer
+//! +0    > S. 0:0(0) ack 101       expect a segment sent to it at that time
+//! +0    write 1024                pass data to the client
+//! +0    close                     end the input of the client
+//! +1    exit ok                   expect the client to finish, or `error`
+//! ```
+//!
+//! Segments are written as flags, `start:end(length)` sequence numbers and
+//! an optional acknowledgment number, which is not checked if omitted.
+//! Flags are `S`, `F`, `R` and `.` for ACK, or `-` for none. The side under
+//! test starts with sequence number zero.
+//!
+//! Scripts run on a virtual clock, so timing is checked precisely and
+//! retransmission timeouts take no time at all.
+
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use tokio::{
+    runtime,
+    sync::{
+        mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
+        Mutex,
+    },
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+use udptcp::{
+    client::{run_client, ClientConfig},
+    packet::{Ack, Flags, Packet, PacketExtra, PseudoPacket, Seq},
+    server::{run_server, ServerConfig},
+    transport::DatagramTransport,
+};
+
+/// How far off the time of an expected segment may be
+const TOLERANCE: Duration = Duration::from_millis(10);
+
+/// Side of a connection a script is run against
+#[derive(Debug, Clone, Copy)]
+pub enum Target {
+    Client,
+    Server,
+}
+
+/// Runs the script `tests/scripts/{name}` against `target`
+pub fn run(name: &str, target: Target) {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests")
+        .join("scripts")
+        .join(name);
+    let result = fs::read_to_string(&path)
+        .map_err(anyhow::Error::from)
+        .and_then(|script| parse(&script))
+        .and_then(|lines| {
+            runtime::Builder::new_current_thread()
+                .enable_all()
+                .start_paused(true)
+                .build()?
+                .block_on(interpret(lines, target))
+        });
+    if let Err(err) = result {
+        panic!("{}: {:#}", name, err);
+    }
+}
+
+fn local() -> SocketAddr {
+    SocketAddr::from(([192, 0, 2, 1], 8080))
+}
+
+fn remote() -> SocketAddr {
+    SocketAddr::from(([192, 0, 2, 2], 40000))
+}
+
+struct Line {
+    number: usize,
+    time: Time,
+    event: Event,
+}
+
+enum Time {
+    Absolute(Duration),
+    Relative(Duration),
+}
+
+enum Event {
+    Inject(Segment),
+    Expect(Segment),
+    Write(usize),
+    Close,
+    Exit(bool),
+}
+
+struct Segment {
+    flags: String,
+    seq: u32,
+    length: usize,
+    ack: Option<u32>,
+}
+
+fn parse(script: &str) -> Result<Vec<Line>> {
+    let mut lines = Vec::new();
+    for (index, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let number = index + 1;
+        let line = parse_line(line)
+            .with_context(|| format!("line {}: cannot parse", number))?;
+        lines.push(Line {
+            number,
+            time: line.0,
+            event: line.1,
+        });
+    }
+    Ok(lines)
+}
+
+fn parse_line(line: &str) -> Result<(Time, Event)> {
+    let mut words = line.split_whitespace();
+    let time = words.next().ok_or(anyhow!("missing time"))?;
+    let time = match time.strip_prefix('+') {
+        Some(time) => Time::Relative(Duration::from_secs_f64(time.parse()?)),
+        None => Time::Absolute(Duration::from_secs_f64(time.parse()?)),
+    };
+    let event = match words.next().ok_or(anyhow!("missing event"))? {
+        "<" => Event::Inject(parse_segment(&mut words)?),
+        ">" => Event::Expect(parse_segment(&mut words)?),
+        "write" => {
+            let length = words.next().ok_or(anyhow!("missing length"))?;
+            Event::Write(length.parse()?)
+        }
+        "close" => Event::Close,
+        "exit" => match words.next() {
+            Some("ok") => Event::Exit(true),
+            Some("error") => Event::Exit(false),
+            _ => bail!("expected `ok` or `error`"),
+        },
+        event => bail!("unknown event {:?}", event),
+    };
+    ensure!(words.next().is_none(), "trailing input");
+    Ok((time, event))
+}
+
+fn parse_segment<'a>(
+    words: &mut impl Iterator<Item = &'a str>,
+) -> Result<Segment> {
+    let flags = words.next().ok_or(anyhow!("missing flags"))?;
+    ensure!(
+        flags == "-" || flags.chars().all(|flag| "SFR.".contains(flag)),
+        "unknown flags {:?}",
+        flags
+    );
+    let range = words.next().ok_or(anyhow!("missing sequence numbers"))?;
+    let (start, rest) = range
+        .split_once(':')
+        .ok_or(anyhow!("expected start:end(length)"))?;
+    let (end, length) = rest
+        .strip_suffix(')')
+        .and_then(|rest| rest.split_once('('))
+        .ok_or(anyhow!("expected start:end(length)"))?;
+    let seq: u32 = start.parse()?;
+    let length: usize = length.parse()?;
+    ensure!(
+        end.parse::<u32>()? == seq.wrapping_add(length as u32),
+        "end does not match the length"
+    );
+    let ack = match words.next() {
+        Some("ack") => {
+            let ack = words.next().ok_or(anyhow!("missing ack"))?;
+            Some(ack.parse()?)
+        }
+        Some(word) => bail!("unexpected {:?}", word),
+        None => None,
+    };
+    Ok(Segment {
+        flags: flags.into(),
+        seq,
+        length,
+        ack,
+    })
+}
+
+impl Segment {
+    fn flags(&self) -> Flags {
+        let mut flags = Flags::default();
+        for flag in self.flags.chars() {
+            flags = match flag {
+                'S' => flags.flip_syn(),
+                'F' => flags.flip_fin(),
+                'R' => flags.flip_rst(),
+                '.' => flags.flip_ack(),
+                _ => flags,
+            };
+        }
+        flags
+    }
+
+    fn packet(&self) -> Packet {
+        Packet::from(PseudoPacket {
+            source: remote(),
+            dest: local(),
+            seq: Seq(self.seq),
+            extra: PacketExtra {
+                ack: Ack(self.ack.unwrap_or_default()),
+                flags: self.flags(),
+                data: vec![0; self.length],
+                ..Default::default()
+            },
+        })
+    }
+
+    fn matches(&self, packet: &Packet) -> bool {
+        let (expected, actual) = (self.flags(), packet.flags());
+        expected.is_syn() == actual.is_syn()
+            && expected.is_ack() == actual.is_ack()
+            && expected.is_fin() == actual.is_fin()
+            && expected.is_rst() == actual.is_rst()
+            && packet.seq().0 == self.seq
+            && packet.data().len() == self.length
+            && self.ack.is_none_or(|ack| packet.acknowledgment().0 == ack)
+    }
+}
+
+impl Display for Segment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let end = self.seq.wrapping_add(self.length as u32);
+        write!(f, "{} {}:{}({})", self.flags, self.seq, end, self.length)?;
+        match self.ack {
+            Some(ack) => write!(f, " ack {}", ack),
+            None => Ok(()),
+        }
+    }
+}
+
+impl From<&Packet> for Segment {
+    fn from(packet: &Packet) -> Self {
+        let flags = packet.flags();
+        let flags: String = [
+            (flags.is_syn(), 'S'),
+            (flags.is_fin(), 'F'),
+            (flags.is_rst(), 'R'),
+            (flags.is_ack(), '.'),
+        ]
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, flag)| flag)
+        .collect();
+        Segment {
+            flags: if flags.is_empty() { "-".into() } else { flags },
+            seq: packet.seq().0,
+            length: packet.data().len(),
+            ack: Some(packet.acknowledgment().0),
+        }
+    }
+}
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Transport of the side under test, connected to the script
+struct FakeTransport {
+    inbox: Mutex<UnboundedReceiver<Datagram>>,
+    outbox: UnboundedSender<(Datagram, Instant)>,
+}
+
+impl DatagramTransport for FakeTransport {
+    async fn send_to(
+        &self,
+        buffer: &[u8],
+        target: SocketAddr,
+    ) -> io::Result<usize> {
+        let _ = self.outbox.send(((buffer.into(), target), Instant::now()));
+        Ok(buffer.len())
+    }
+
+    async fn recv_from(
+        &self,
+        buffer: &mut [u8],
+    ) -> io::Result<(usize, SocketAddr)> {
+        let (data, source) = self
+            .inbox
+            .lock()
+            .await
+            .recv()
+            .await
+            .ok_or_else(|| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        buffer[..data.len()].copy_from_slice(&data);
+        Ok((data.len(), source))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(local())
+    }
+}
+
+async fn interpret(lines: Vec<Line>, target: Target) -> Result<()> {
+    let (inject, inbox) = mpsc::unbounded_channel();
+    let (outbox, mut sent) = mpsc::unbounded_channel();
+    let transport = FakeTransport {
+        inbox: Mutex::new(inbox),
+        outbox,
+    };
+    let isn = Arc::new(|_, _| Seq(0));
+    let (mut input, mut task): (Option<Sender<_>>, JoinHandle<Result<()>>) =
+        match target {
+            Target::Client => {
+                let (input, chunks) = mpsc::channel(64);
+                let config = ClientConfig {
+                    isn: Some(isn),
+                    ..Default::default()
+                };
+                let client = run_client(transport, remote(), chunks, config);
+                (Some(input), tokio::spawn(client))
+            }
+            Target::Server => {
+                let config = ServerConfig {
+                    isn: Some(isn),
+                    ..Default::default()
+                };
+                (None, tokio::spawn(run_server(transport, config)))
+            }
+        };
+
+    let start = Instant::now();
+    let mut now = Duration::ZERO;
+    for Line {
+        number,
+        time,
+        event,
+    } in lines
+    {
+        now = match time {
+            Time::Absolute(time) => time,
+            Time::Relative(time) => now + time,
+        };
+        let at = start + now;
+        let context = || format!("line {}", number);
+        match event {
+            Event::Inject(segment) => {
+                time::sleep_until(at).await;
+                let packet = segment.packet().into_bytes();
+                inject.send((packet, remote())).with_context(context)?;
+            }
+            Event::Expect(segment) => {
+                let next = time::timeout_at(at + TOLERANCE, sent.recv());
+                let ((bytes, address), sent_at) = next
+                    .await
+                    .ok()
+                    .flatten()
+                    .ok_or(anyhow!("expected {}, nothing was sent", segment))
+                    .with_context(context)?;
+                let packet = Packet::from_bytes(bytes)?;
+                let actual = Segment::from(&packet);
+                ensure!(
+                    address == remote() && segment.matches(&packet),
+                    "{}: expected {}, sent {} to {}",
+                    context(),
+                    segment,
+                    actual,
+                    address
+                );
+                ensure!(
+                    sent_at + TOLERANCE >= at,
+                    "{}: {} sent {:?} too early",
+                    context(),
+                    actual,
+                    at - sent_at
+                );
+            }
+            Event::Write(length) => {
+                time::sleep_until(at).await;
+                let input = input.as_ref().ok_or(anyhow!("not a client"));
+                input
+                    .with_context(context)?
+                    .send(Ok(vec![0; length]))
+                    .await?;
+            }
+            Event::Close => {
+                time::sleep_until(at).await;
+                input
+                    .take()
+                    .ok_or(anyhow!("not a client or closed twice"))?;
+            }
+            Event::Exit(ok) => {
+                let result = time::timeout_at(at + TOLERANCE, &mut task)
+                    .await
+                    .map_err(|_| anyhow!("{}: still running", context()))??;
+                ensure!(
+                    result.is_ok() == ok,
+                    "{}: finished with {:?}",
+                    context(),
+                    result
+                );
+            }
+        }
+    }
+    task.abort();
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tokio::{
+    net::UdpSocket,
+    sync::mpsc::{self, Sender},
+    task::JoinHandle,
+    time,
+};
+
+use udptcp::{
+    client::run_client,
+    server::{Listener, ServerConfig, ShutdownHandle, Sink, Summary},
+    stats::ConnectionStats,
+};
+
+async fn start(
+    sink: Arc<dyn Sink>,
+) -> (SocketAddr, ShutdownHandle, JoinHandle<Summary>) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = socket.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(sink),
+        ..Default::default()
+    };
+    let listener = Listener::new(socket, config);
+    let handle = listener.shutdown_handle();
+    let task = tokio::spawn(async move { listener.run().await.unwrap() });
+    (address, handle, task)
+}
+
+/// Connects a client sending whatever is written into the returned sender
+async fn connect(
+    server: SocketAddr,
+) -> (
+    Sender<std::io::Result<Vec<u8>>>,
+    JoinHandle<anyhow::Result<()>>,
+) {
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (input, chunks) = mpsc::channel(1);
+    let client = run_client(socket, server, chunks, Default::default());
+    let client = tokio::spawn(client);
+    input.send(Ok(b"hello".to_vec())).await.unwrap();
+    // the chunk is taken once the connection is established
+    input.reserve().await.unwrap();
+    (input, client)
+}
+
+#[tokio::test]
+async fn connections_finish_within_the_deadline() {
+    let (server, handle, listener) = start(Arc::new(|_, _| {})).await;
+    let (input, client) = connect(server).await;
+
+    handle.shutdown(Duration::from_secs(10));
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let (_input, chunks) = mpsc::channel(1);
+    let refused = run_client(socket, server, chunks, Default::default());
+    let refused = time::timeout(Duration::from_secs(5), refused).await;
+    assert!(refused.unwrap().is_err(), "new connection accepted");
+
+    drop(input);
+    client.await.unwrap().unwrap();
+    let summary = time::timeout(Duration::from_secs(5), listener)
+        .await
+        .expect("listener still running")
+        .unwrap();
+    assert_eq!(
+        summary,
+        Summary {
+            accepted: 1,
+            refused: 1,
+            closed: 1,
+            failed: 0,
+            aborted: 0,
+        }
+    );
+}
+
+#[tokio::test]
+async fn connections_are_reset_past_the_deadline() {
+    let (server, handle, listener) = start(Arc::new(|_, _| {})).await;
+    let (input, client) = connect(server).await;
+
+    handle.shutdown(Duration::from_millis(100));
+    let summary = time::timeout(Duration::from_secs(5), listener)
+        .await
+        .expect("listener still running")
+        .unwrap();
+    assert_eq!(summary.aborted, 1);
+    assert_eq!(summary.closed, 0);
+
+    input.send(Ok(b"too late".to_vec())).await.unwrap();
+    let client = time::timeout(Duration::from_secs(5), client).await;
+    assert!(client.unwrap().unwrap().is_err(), "reset went unnoticed");
+}
+
+/// Takes its time to close, recording whether every connection closed
+/// cleanly
+#[derive(Default)]
+struct SlowClose(Mutex<Vec<bool>>);
+
+impl Sink for SlowClose {
+    fn receive(&self, _peer: SocketAddr, _data: Vec<u8>) {}
+
+    fn close(&self, _peer: SocketAddr, clean: bool, _stats: &ConnectionStats) {
+        thread::sleep(Duration::from_millis(300));
+        self.0.lock().unwrap().push(clean);
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn connections_ending_at_the_deadline_are_not_reset() {
+    let sink = Arc::new(SlowClose::default());
+    let (server, handle, listener) = start(sink.clone()).await;
+    let (input, client) = connect(server).await;
+
+    // the deadline passes while the sink closes the connection
+    handle.shutdown(Duration::from_millis(100));
+    drop(input);
+    client.await.unwrap().unwrap();
+    let summary = time::timeout(Duration::from_secs(5), listener)
+        .await
+        .expect("listener still running")
+        .unwrap();
+    assert_eq!(summary.closed, 1);
+    assert_eq!(summary.aborted, 0);
+    assert_eq!(*sink.0.lock().unwrap(), [true]);
+}
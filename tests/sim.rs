@@ -0,0 +1,245 @@
+use std::{
+    io::Cursor,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::AsyncReadExt,
+    sync::mpsc,
+    time::{self, Instant},
+};
+
+use udptcp::{
+    client::{read_chunks_async, run_client, ClientConfig},
+    server::{run_server, Reply, ServerConfig, Sink},
+    sim::{simulate, LinkConfig, Network, SimSocket, Simulation},
+    socket::CHUNK_SIZE,
+    stats::{ConnectionStats, Stats},
+    transport::DatagramTransport,
+};
+
+/// Two sockets of a network with the impairments of `link`
+fn pair(link: LinkConfig) -> (SimSocket, SimSocket) {
+    let network = Network::new(link, 1).unwrap();
+    let a = network.bind(SocketAddr::from(([10, 0, 0, 1], 1))).unwrap();
+    let b = network.bind(SocketAddr::from(([10, 0, 0, 2], 2))).unwrap();
+    (a, b)
+}
+
+/// Datagrams `socket` receives within a virtual second
+async fn drain(socket: &SimSocket) -> Vec<(Vec<u8>, Instant)> {
+    let mut received = Vec::new();
+    let mut buffer = [0; 16];
+    while let Ok(Ok((length, _))) =
+        time::timeout(Duration::from_secs(1), socket.recv_from(&mut buffer))
+            .await
+    {
+        received.push((buffer[..length].to_vec(), Instant::now()));
+    }
+    received
+}
+
+#[test]
+fn invalid_links_are_refused() {
+    for probability in [-0.1, 1.5, f64::NAN] {
+        let links = [
+            LinkConfig {
+                loss: probability,
+                ..Default::default()
+            },
+            LinkConfig {
+                corrupt: probability,
+                ..Default::default()
+            },
+        ];
+        for link in links {
+            assert!(Network::new(link, 0).is_err());
+        }
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn links_delay_duplicate_and_drop() {
+    let (a, b) = pair(LinkConfig {
+        delay: Duration::from_millis(50),
+        duplicate: 1.0,
+        ..Default::default()
+    });
+    let sent = Instant::now();
+    a.send_to(b"ping", b.local_addr().unwrap()).await.unwrap();
+    let received = drain(&b).await;
+    assert_eq!(received.len(), 2);
+    for (data, at) in received {
+        assert_eq!(data, b"ping");
+        assert_eq!(at - sent, Duration::from_millis(50));
+    }
+
+    let (a, b) = pair(LinkConfig {
+        loss: 1.0,
+        ..Default::default()
+    });
+    a.send_to(b"lost", b.local_addr().unwrap()).await.unwrap();
+    assert!(drain(&b).await.is_empty());
+}
+
+fn payload() -> Vec<u8> {
+    (0..20 * CHUNK_SIZE).map(|i| (i * 7 % 253) as u8).collect()
+}
+
+#[tokio::test(start_paused = true)]
+async fn corrupted_segments_are_retransmitted() {
+    let link = LinkConfig {
+        corrupt: 0.2,
+        ..Default::default()
+    };
+    let network = Network::new(link, 5).unwrap();
+    let address = SocketAddr::from(([10, 0, 0, 1], 7));
+    let server = network.bind(address).unwrap();
+    let client = network.bind(SocketAddr::from(([10, 0, 0, 2], 0))).unwrap();
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let sink = received.clone();
+    let config = ServerConfig {
+        sink: Some(Arc::new(move |_, data: Vec<u8>| {
+            sink.lock().unwrap().extend(data)
+        })),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let stats = Stats::default();
+    let config = ClientConfig {
+        stats: Some(stats.clone()),
+        ..Default::default()
+    };
+    let payload = payload();
+    let chunks = read_chunks_async(Cursor::new(payload.clone()));
+    run_client(client, address, chunks, config).await.unwrap();
+    server.abort();
+
+    assert!(
+        *received.lock().unwrap() == payload,
+        "corrupted data delivered"
+    );
+    assert!(stats.snapshot().retransmissions > 0);
+}
+
+#[test]
+fn lossy_transfers_replay_in_virtual_time() {
+    let simulation = Simulation {
+        link: LinkConfig {
+            loss: 0.1,
+            delay: Duration::from_millis(40),
+            jitter: Duration::from_millis(20),
+            reorder: 0.05,
+            duplicate: 0.05,
+            corrupt: 0.05,
+        },
+        seed: 11,
+        payload: payload(),
+        deadline: Duration::from_secs(10 * 60),
+    };
+    let started = std::time::Instant::now();
+    let first = simulate(simulation.clone()).unwrap();
+    let wall = started.elapsed();
+    let second = simulate(simulation).unwrap();
+
+    assert!(
+        first.received == payload(),
+        "data differs from the sent one"
+    );
+    assert!(second.received == first.received);
+    assert_eq!(first.elapsed, second.elapsed);
+    // 20 round trips at least, without a second of waiting
+    assert!(first.elapsed > Duration::from_millis(20 * 80));
+    assert!(wall < first.elapsed, "{:?} took {:?}", first.elapsed, wall);
+}
+
+#[test]
+fn simulations_give_up_at_the_deadline() {
+    let simulation = Simulation {
+        link: LinkConfig {
+            loss: 1.0,
+            ..Default::default()
+        },
+        payload: payload(),
+        deadline: Duration::from_secs(10 * 60),
+        ..Default::default()
+    };
+    assert!(simulate(simulation).is_err());
+}
+
+/// Sends the payload to every peer as soon as it connects and ends the
+/// replies right after, recording what it receives and whether every
+/// connection closed cleanly
+#[derive(Default)]
+struct Greeter {
+    received: Mutex<Vec<u8>>,
+    closed: Mutex<Vec<bool>>,
+}
+
+impl Sink for Greeter {
+    fn open(&self, _peer: SocketAddr, reply: Reply) {
+        reply.send(payload());
+        reply.finish();
+    }
+
+    fn receive(&self, _peer: SocketAddr, data: Vec<u8>) {
+        self.received.lock().unwrap().extend(data)
+    }
+
+    fn close(&self, _peer: SocketAddr, clean: bool, _stats: &ConnectionStats) {
+        self.closed.lock().unwrap().push(clean);
+    }
+}
+
+#[tokio::test(start_paused = true)]
+async fn servers_may_close_first_over_lossy_links() {
+    let link = LinkConfig {
+        loss: 0.1,
+        delay: Duration::from_millis(40),
+        jitter: Duration::from_millis(20),
+        duplicate: 0.05,
+        ..Default::default()
+    };
+    let network = Network::new(link, 3).unwrap();
+    let address = SocketAddr::from(([10, 0, 0, 1], 7));
+    let server = network.bind(address).unwrap();
+    let client = network.bind(SocketAddr::from(([10, 0, 0, 2], 0))).unwrap();
+    let sink = Arc::new(Greeter::default());
+    let config = ServerConfig {
+        sink: Some(sink.clone()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let (output, mut replies) = tokio::io::duplex(CHUNK_SIZE);
+    let greeting = tokio::spawn(async move {
+        let mut data = Vec::new();
+        replies.read_to_end(&mut data).await.unwrap();
+        data
+    });
+    let config = ClientConfig {
+        output: Some(Box::new(output)),
+        ..Default::default()
+    };
+    let (input, chunks) = mpsc::channel(1);
+    let client = tokio::spawn(run_client(client, address, chunks, config));
+    let payload = payload();
+    let mut data = payload.chunks(CHUNK_SIZE).map(<[u8]>::to_vec);
+    // the server completes the handshake once the client sends something
+    input.send(Ok(data.next().unwrap())).await.unwrap();
+    // the greeting ends long before the data of the client does
+    assert!(greeting.await.unwrap() == payload, "greeting differs");
+    for chunk in data {
+        input.send(Ok(chunk)).await.unwrap();
+    }
+    drop(input);
+    client.await.unwrap().unwrap();
+    time::sleep(Duration::from_secs(5)).await;
+    server.abort();
+
+    assert!(*sink.received.lock().unwrap() == payload, "data differs");
+    assert_eq!(*sink.closed.lock().unwrap(), [true]);
+}
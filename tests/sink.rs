@@ -0,0 +1,268 @@
+mod common;
+
+use std::{
+    env, fs,
+    io::Cursor,
+    net::SocketAddr,
+    process,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::UdpSocket,
+    sync::mpsc,
+    task::JoinHandle,
+    time,
+};
+
+use udptcp::{
+    client::{read_chunks_async, run_client, ClientConfig, Output},
+    server::{run_server, Reply, ServerConfig, Sink},
+    sim::{LinkConfig, Network},
+    sink::{Directory, Echo, Netcat},
+    socket::CHUNK_SIZE,
+    stats::{ConnectionStats, Stats},
+    transport::{DatagramTransport, UnixTransport},
+};
+
+use common::Closed;
+
+/// Output of a client collecting whatever the server sends back
+fn collect() -> (Output, JoinHandle<Vec<u8>>) {
+    let (writer, mut reader) = io::duplex(CHUNK_SIZE);
+    let collected = tokio::spawn(async move {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data).await.unwrap();
+        data
+    });
+    (Box::new(writer), collected)
+}
+
+fn payload() -> Vec<u8> {
+    (0..5 * CHUNK_SIZE / 2).map(|i| (i % 251) as u8).collect()
+}
+
+async fn echo<T: DatagramTransport>(server: T, client: T) -> Vec<u8> {
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Echo::default())),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+    let (output, echoed) = collect();
+    let config = ClientConfig {
+        output: Some(output),
+        ..Default::default()
+    };
+    let chunks = read_chunks_async(Cursor::new(payload()));
+    run_client(client, address, chunks, config).await.unwrap();
+    server.abort();
+    echoed.await.unwrap()
+}
+
+#[tokio::test]
+async fn echo_sends_the_data_back() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    assert!(echo(server, client).await == payload());
+}
+
+#[tokio::test(start_paused = true)]
+async fn echo_survives_a_lossy_link() {
+    let link = LinkConfig {
+        loss: 0.2,
+        delay: Duration::from_millis(20),
+        jitter: Duration::from_millis(10),
+        reorder: 0.1,
+        duplicate: 0.1,
+        ..Default::default()
+    };
+    let network = Network::new(link, 7).unwrap();
+    let server = network.bind(SocketAddr::from(([10, 0, 0, 1], 7))).unwrap();
+    let client = network.bind(SocketAddr::from(([10, 0, 0, 2], 0))).unwrap();
+    let echoed = time::timeout(Duration::from_secs(600), echo(server, client));
+    assert!(echoed.await.unwrap() == payload());
+}
+
+#[tokio::test]
+async fn echo_runs_over_unix_sockets() {
+    let directory =
+        env::temp_dir().join(format!("udptcp-unix-{}", process::id()));
+    fs::create_dir_all(&directory).unwrap();
+    let address = SocketAddr::from(([127, 0, 0, 1], 7));
+    let server = UnixTransport::bind(&directory, address).unwrap();
+    let address = SocketAddr::from(([127, 0, 0, 1], 0));
+    let client = UnixTransport::bind(&directory, address).unwrap();
+    assert_ne!(client.local_addr().unwrap().port(), 0);
+    assert!(echo(server, client).await == payload());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn directory_keeps_a_file_per_connection() {
+    let directory =
+        env::temp_dir().join(format!("udptcp-sink-{}", process::id()));
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (sink, mut closed) =
+        Closed::new(Directory::create(&directory).unwrap());
+    let config = ServerConfig {
+        sink: Some(Arc::new(sink)),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let mut peers = Vec::new();
+    for payload in [payload(), b"second".to_vec()] {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        peers.push((socket.local_addr().unwrap(), payload.clone()));
+        let chunks = read_chunks_async(Cursor::new(payload));
+        run_client(socket, address, chunks, Default::default())
+            .await
+            .unwrap();
+        closed.recv().await.unwrap();
+    }
+    server.abort();
+
+    let files: Vec<_> = fs::read_dir(&directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect();
+    assert_eq!(files.len(), 2, "{:?}", files);
+    for (peer, payload) in peers {
+        let suffix = format!("-{}", peer);
+        let file = files
+            .iter()
+            .find(|file| file.to_str().unwrap().ends_with(&suffix))
+            .unwrap_or_else(|| panic!("no file of {} in {:?}", peer, files));
+        assert!(fs::read(file).unwrap() == payload);
+    }
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+/// Snapshots the statistics of the connection with every chunk received
+struct Live {
+    reply: Mutex<Option<Reply>>,
+    snapshots: Arc<Mutex<Vec<ConnectionStats>>>,
+}
+
+impl Sink for Live {
+    fn open(&self, _peer: SocketAddr, reply: Reply) {
+        *self.reply.lock().unwrap() = Some(reply);
+    }
+
+    fn receive(&self, _peer: SocketAddr, _data: Vec<u8>) {
+        let reply = self.reply.lock().unwrap();
+        let stats = reply.as_ref().unwrap().stats().snapshot();
+        self.snapshots.lock().unwrap().push(stats);
+    }
+
+    fn finish(&self, _peer: SocketAddr) {
+        self.reply.lock().unwrap().take();
+    }
+}
+
+#[tokio::test]
+async fn sinks_follow_live_statistics() {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let snapshots = Arc::new(Mutex::new(Vec::new()));
+    let (sink, mut closed) = Closed::new(Live {
+        reply: Mutex::new(None),
+        snapshots: snapshots.clone(),
+    });
+    let config = ServerConfig {
+        sink: Some(Arc::new(sink)),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let chunks = read_chunks_async(Cursor::new(vec![7; 3 * CHUNK_SIZE]));
+    run_client(socket, address, chunks, Default::default())
+        .await
+        .unwrap();
+    assert!(closed.recv().await.unwrap());
+    server.abort();
+
+    let snapshots = snapshots.lock().unwrap();
+    assert_eq!(snapshots.len(), 3);
+    for (i, stats) in snapshots.iter().enumerate() {
+        assert_eq!(stats.state, "ESTABLISHED");
+        assert!(stats.bytes_received >= ((i + 1) * CHUNK_SIZE) as u64);
+    }
+}
+
+/// Talks with a netcat server, the input of the server ending before that
+/// of the client if `held` is `None`, and that long after it otherwise,
+/// with no retransmissions meanwhile
+async fn netcat(held: Option<Duration>) {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let (server_input, chunks) = mpsc::channel(1);
+    let received = Arc::new(Mutex::new(Vec::new()));
+    let output = received.clone();
+    let netcat = Arc::new(Netcat::new(chunks, move |_, data: Vec<u8>| {
+        output.lock().unwrap().extend(data)
+    }));
+    let config = ServerConfig {
+        sink: Some(netcat.clone()),
+        ..Default::default()
+    };
+    let server = tokio::spawn(run_server(server, config));
+
+    let (output, printed) = collect();
+    let stats = Stats::default();
+    let config = ClientConfig {
+        output: Some(output),
+        stats: Some(stats.clone()),
+        ..Default::default()
+    };
+    let (client_input, chunks) = mpsc::channel(1);
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let client = tokio::spawn(run_client(socket, address, chunks, config));
+    client_input.send(Ok(b"hello".to_vec())).await.unwrap();
+    server_input.send(Ok(b"hi".to_vec())).await.unwrap();
+    if let Some(held) = held {
+        client_input.send(Ok(b" there".to_vec())).await.unwrap();
+        drop(client_input);
+        time::sleep(Duration::from_millis(50)).await;
+        let retransmissions = stats.snapshot().retransmissions;
+        time::sleep(held).await;
+        // the FIN of the client, acknowledged by now, is not sent again
+        assert_eq!(stats.snapshot().retransmissions, retransmissions);
+        server_input.send(Ok(b" you".to_vec())).await.unwrap();
+        drop(server_input);
+        let client = time::timeout(Duration::from_secs(5), client).await;
+        client.expect("client still running").unwrap().unwrap();
+        assert_eq!(printed.await.unwrap(), b"hi you");
+    } else {
+        drop(server_input);
+        let printed = time::timeout(Duration::from_secs(5), printed).await;
+        assert_eq!(printed.expect("FIN of the server lost").unwrap(), b"hi");
+        client_input.send(Ok(b" there".to_vec())).await.unwrap();
+        drop(client_input);
+        client.await.unwrap().unwrap();
+    }
+    let closed = time::timeout(Duration::from_secs(5), netcat.closed()).await;
+    closed.expect("connection still open");
+    server.abort();
+    assert_eq!(*received.lock().unwrap(), b"hello there");
+}
+
+#[tokio::test]
+async fn netcat_servers_may_close_first() {
+    netcat(None).await;
+}
+
+#[tokio::test]
+async fn netcat_clients_may_close_first() {
+    netcat(Some(Duration::from_millis(100))).await;
+}
+
+#[tokio::test]
+async fn netcat_servers_may_keep_stdin_open() {
+    netcat(Some(Duration::from_secs(1))).await;
+}
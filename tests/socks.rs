@@ -0,0 +1,184 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::oneshot,
+    time,
+};
+
+use udptcp::{
+    server::{run_server, ServerConfig},
+    socks::{proxy, Dialer},
+};
+
+/// Destination sending back whatever it reads
+async fn destination() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+            });
+        }
+    });
+    address
+}
+
+/// Destination greeting the application and closing its write half right
+/// away, then handing what it reads over the returned receiver
+async fn greeter() -> (SocketAddr, oneshot::Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        tx.send(request).unwrap();
+    });
+    (address, rx)
+}
+
+/// Destination handing the outcome of reading until the end over the
+/// returned receiver
+async fn reader() -> (SocketAddr, oneshot::Receiver<std::io::Result<usize>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        tx.send(stream.read_to_end(&mut request).await).unwrap();
+    });
+    (address, rx)
+}
+
+/// Starts both ends of the proxy, returning the address of the SOCKS5
+/// server
+async fn socks() -> SocketAddr {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Dialer::default())),
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(proxy(listener, server_address, Default::default));
+    address
+}
+
+/// Sends a request with `command` for the encoded `target`, returning the
+/// stream and the reply code
+async fn request(
+    socks: SocketAddr,
+    command: u8,
+    target: &[u8],
+) -> (TcpStream, u8) {
+    let mut stream = TcpStream::connect(socks).await.unwrap();
+    stream.write_all(&[5, 2, 2, 0]).await.unwrap();
+    let mut method = [0; 2];
+    stream.read_exact(&mut method).await.unwrap();
+    assert_eq!(method, [5, 0]);
+    stream.write_all(&[5, command, 0]).await.unwrap();
+    stream.write_all(target).await.unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).await.unwrap();
+    assert_eq!(reply[0], 5);
+    (stream, reply[1])
+}
+
+fn ipv4(address: SocketAddr) -> Vec<u8> {
+    let mut target = vec![1, 127, 0, 0, 1];
+    target.extend(address.port().to_be_bytes());
+    target
+}
+
+async fn exchange(mut stream: TcpStream) {
+    let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+    stream.write_all(&data).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut echoed = Vec::new();
+    stream.read_to_end(&mut echoed).await.unwrap();
+    assert!(echoed == data);
+}
+
+#[tokio::test]
+async fn connects_to_addresses() {
+    let target = ipv4(destination().await);
+    let (stream, code) = request(socks().await, 1, &target).await;
+    assert_eq!(code, 0);
+    exchange(stream).await;
+}
+
+#[tokio::test]
+async fn connects_to_domains() {
+    let port = destination().await.port();
+    let mut target = vec![3, 9];
+    target.extend(b"localhost");
+    target.extend(port.to_be_bytes());
+    let (stream, code) = request(socks().await, 1, &target).await;
+    assert_eq!(code, 0);
+    exchange(stream).await;
+}
+
+#[tokio::test]
+async fn destinations_may_close_first() {
+    let (destination, received) = greeter().await;
+    let target = ipv4(destination);
+    let (mut stream, code) = request(socks().await, 1, &target).await;
+    assert_eq!(code, 0);
+    let mut greeting = Vec::new();
+    let read = stream.read_to_end(&mut greeting);
+    time::timeout(Duration::from_secs(5), read)
+        .await
+        .expect("end of the destination data never arrived")
+        .unwrap();
+    assert_eq!(greeting, b"hello");
+
+    // the other direction stays open
+    stream.write_all(b"still there").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let received = time::timeout(Duration::from_secs(5), received).await;
+    assert_eq!(received.unwrap().unwrap(), b"still there");
+}
+
+#[tokio::test]
+async fn applications_disconnecting_close_the_destination() {
+    let (destination, ended) = reader().await;
+    let target = ipv4(destination);
+    let (mut stream, code) = request(socks().await, 1, &target).await;
+    assert_eq!(code, 0);
+    stream.write_all(&[7; 3000]).await.unwrap();
+    stream.set_zero_linger().unwrap();
+    drop(stream);
+
+    let ended = time::timeout(Duration::from_secs(5), ended).await;
+    let ended = ended.expect("destination connection still open");
+    // with EOF or a reset alike
+    let _ = ended.unwrap();
+}
+
+#[tokio::test]
+async fn refused_connections_are_reported() {
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let target = ipv4(closed.local_addr().unwrap());
+    drop(closed);
+    let (mut stream, code) = request(socks().await, 1, &target).await;
+    assert_eq!(code, 5);
+    assert_eq!(stream.read(&mut [0; 1]).await.unwrap(), 0);
+}
+
+#[tokio::test]
+async fn only_connect_is_supported() {
+    let target = ipv4(destination().await);
+    let (_, code) = request(socks().await, 2, &target).await;
+    assert_eq!(code, 7);
+}
@@ -0,0 +1,148 @@
+use std::{
+    env, fs,
+    net::SocketAddr,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+use tokio::{
+    io::{self, AsyncReadExt},
+    net::UdpSocket,
+    sync::mpsc,
+};
+
+use udptcp::{
+    client::{run_client, ClientConfig},
+    server::{run_server, ServerConfig},
+    socket::CHUNK_SIZE,
+    transfer::{send_file, Status, Uploads},
+};
+
+/// Directory of its own for every test
+fn directory(test: &str) -> PathBuf {
+    let directory = env::temp_dir().join(format!(
+        "udptcp-transfer-{}-{}",
+        test,
+        process::id()
+    ));
+    let _ = fs::remove_dir_all(&directory);
+    directory
+}
+
+async fn serve(directory: &Path) -> SocketAddr {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Uploads::create(directory).unwrap())),
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+    address
+}
+
+fn header(name: &str, size: u64, mode: u32) -> Vec<u8> {
+    let mut header = (name.len() as u16).to_be_bytes().to_vec();
+    header.extend(name.as_bytes());
+    header.extend(size.to_be_bytes());
+    header.extend(mode.to_be_bytes());
+    header
+}
+
+/// Sends `chunks` as they are and returns the status the server answered
+async fn upload(address: SocketAddr, chunks: Vec<Vec<u8>>) -> Status {
+    let (tx, rx) = mpsc::channel(chunks.len());
+    for chunk in chunks {
+        tx.send(Ok(chunk)).await.unwrap();
+    }
+    drop(tx);
+    let (writer, mut reader) = io::duplex(CHUNK_SIZE);
+    let config = ClientConfig {
+        output: Some(Box::new(writer)),
+        ..Default::default()
+    };
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    run_client(socket, address, rx, config).await.unwrap();
+    let mut answer = Vec::new();
+    reader.read_to_end(&mut answer).await.unwrap();
+    assert_eq!(answer.len(), 1, "{:?}", answer);
+    Status::from_code(answer[0]).unwrap()
+}
+
+fn entries(directory: &Path) -> Vec<String> {
+    let mut entries: Vec<_> = fs::read_dir(directory)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    entries.sort();
+    entries
+}
+
+#[tokio::test]
+async fn files_are_stored_with_their_mode() {
+    let directory = directory("stored");
+    let address = serve(&directory.join("received")).await;
+    let path = directory.join("data.bin");
+    let content: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    fs::write(&path, &content).unwrap();
+    fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+
+    let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    send_file(socket, address, &path, Default::default())
+        .await
+        .unwrap();
+
+    let received = directory.join("received");
+    assert_eq!(entries(&received), ["data.bin"]);
+    let stored = received.join("data.bin");
+    assert!(fs::read(&stored).unwrap() == content);
+    let mode = fs::metadata(&stored).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o640);
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn corrupted_uploads_are_refused() {
+    let directory = directory("corrupted");
+    let address = serve(&directory).await;
+    let content = b"hello, world".to_vec();
+    let mut hash = Sha256::digest(&content).to_vec();
+    hash[0] ^= 1;
+
+    let chunks = vec![header("hello", 12, 0o644), content.clone(), hash];
+    assert_eq!(upload(address, chunks).await, Status::ChecksumMismatch);
+    let chunks = vec![header("hello", 20, 0o644), content];
+    assert_eq!(upload(address, chunks).await, Status::Truncated);
+    assert!(entries(&directory).is_empty());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn names_must_be_bare() {
+    let directory = directory("names");
+    let address = serve(&directory.join("received")).await;
+    for name in ["../escape", "", ".."] {
+        let chunks = vec![header(name, 0, 0o644), Sha256::digest(b"").to_vec()];
+        assert_eq!(upload(address, chunks).await, Status::InvalidName);
+    }
+    assert_eq!(entries(&directory), ["received"]);
+    assert!(entries(&directory.join("received")).is_empty());
+    fs::remove_dir_all(&directory).unwrap();
+}
+
+#[tokio::test]
+async fn names_may_take_the_whole_length() {
+    let directory = directory("long");
+    let address = serve(&directory).await;
+    let name = "n".repeat(255);
+    let content = b"long name".to_vec();
+    let hash = Sha256::digest(&content).to_vec();
+
+    let chunks = vec![header(&name, 9, 0o644), content.clone(), hash];
+    assert_eq!(upload(address, chunks).await, Status::Stored);
+    assert_eq!(entries(&directory), [name.as_str()]);
+    assert!(fs::read(directory.join(&name)).unwrap() == content);
+    fs::remove_dir_all(&directory).unwrap();
+}
@@ -0,0 +1,166 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::oneshot,
+    time,
+};
+
+use udptcp::{
+    server::{run_server, ServerConfig},
+    tunnel::{forward, Backend},
+};
+
+/// Backend answering every connection, once it has read all of it, with
+/// the data reversed and repeated `times`
+async fn backend(times: usize) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut request = Vec::new();
+                stream.read_to_end(&mut request).await.unwrap();
+                request.reverse();
+                for _ in 0..times {
+                    stream.write_all(&request).await.unwrap();
+                }
+            });
+        }
+    });
+    address
+}
+
+/// Starts both ends of a tunnel to `backend`, returning the address the
+/// client listens on
+async fn tunnel(backend: SocketAddr) -> SocketAddr {
+    let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    let server_address = server.local_addr().unwrap();
+    let config = ServerConfig {
+        sink: Some(Arc::new(Backend::new(backend.to_string()))),
+        ..Default::default()
+    };
+    tokio::spawn(run_server(server, config));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    tokio::spawn(forward(listener, server_address, Default::default));
+    address
+}
+
+async fn request(tunnel: SocketAddr, request: &[u8]) -> Vec<u8> {
+    let mut stream = TcpStream::connect(tunnel).await.unwrap();
+    stream.write_all(request).await.unwrap();
+    stream.shutdown().await.unwrap();
+    let mut response = Vec::new();
+    stream.read_to_end(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn connections_reach_the_backend() {
+    let tunnel = tunnel(backend(1).await).await;
+    let requests: Vec<Vec<u8>> =
+        (0..3u8).map(|i| vec![i; 3000 + i as usize]).collect();
+    let responses: Vec<_> = requests
+        .iter()
+        .cloned()
+        .map(|data| tokio::spawn(async move { request(tunnel, &data).await }))
+        .collect();
+    for (mut data, response) in requests.into_iter().zip(responses) {
+        data.reverse();
+        assert!(response.await.unwrap() == data);
+    }
+}
+
+#[tokio::test]
+async fn responses_larger_than_the_backlog_arrive_whole() {
+    let tunnel = tunnel(backend(400).await).await;
+    let data: Vec<u8> = (0..=255).collect();
+    let response = request(tunnel, &data).await;
+    assert_eq!(response.len(), 400 * data.len());
+    assert!(response
+        .chunks(data.len())
+        .all(|chunk| chunk.iter().rev().eq(&data)));
+}
+
+/// Backend greeting every connection and closing its write half right
+/// away, then handing what it reads over the returned receiver
+async fn greeter() -> (SocketAddr, oneshot::Receiver<Vec<u8>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        stream.shutdown().await.unwrap();
+        let mut request = Vec::new();
+        stream.read_to_end(&mut request).await.unwrap();
+        tx.send(request).unwrap();
+    });
+    (address, rx)
+}
+
+#[tokio::test]
+async fn backends_may_close_first() {
+    let (backend, request) = greeter().await;
+    let tunnel = tunnel(backend).await;
+    let mut stream = TcpStream::connect(tunnel).await.unwrap();
+    let mut greeting = Vec::new();
+    let read = stream.read_to_end(&mut greeting);
+    time::timeout(Duration::from_secs(5), read)
+        .await
+        .expect("end of the backend data never arrived")
+        .unwrap();
+    assert_eq!(greeting, b"hello");
+
+    // the other direction stays open
+    stream.write_all(b"still there").await.unwrap();
+    stream.shutdown().await.unwrap();
+    let request = time::timeout(Duration::from_secs(5), request).await;
+    assert_eq!(request.unwrap().unwrap(), b"still there");
+}
+
+#[tokio::test]
+async fn refused_backends_reset_the_connection() {
+    let closed = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let tunnel = tunnel(closed.local_addr().unwrap()).await;
+    drop(closed);
+    let mut stream = TcpStream::connect(tunnel).await.unwrap();
+    // without shutting down the write half, which would close cleanly
+    let mut buffer = [0; 1];
+    let read = time::timeout(Duration::from_secs(5), stream.read(&mut buffer));
+    let read = read.await.expect("connection still open");
+    assert!(!matches!(read, Ok(length) if length > 0), "{:?}", read);
+}
+
+/// Backend handing over the returned receiver how reading from its first
+/// connection ended
+async fn reader() -> (SocketAddr, oneshot::Receiver<std::io::Result<usize>>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let address = listener.local_addr().unwrap();
+    let (tx, rx) = oneshot::channel();
+    tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        tx.send(stream.read_to_end(&mut request).await).unwrap();
+    });
+    (address, rx)
+}
+
+#[tokio::test]
+async fn clients_dropping_mid_stream_close_the_backend() {
+    let (backend, ended) = reader().await;
+    let tunnel = tunnel(backend).await;
+    let mut stream = TcpStream::connect(tunnel).await.unwrap();
+    stream.write_all(&[7; 3000]).await.unwrap();
+    // resets the connection instead of closing it
+    stream.set_zero_linger().unwrap();
+    drop(stream);
+
+    let ended = time::timeout(Duration::from_secs(5), ended).await;
+    let ended = ended.expect("backend connection still open");
+    // with EOF or a reset alike
+    let _ = ended.unwrap();
+}